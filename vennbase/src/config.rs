@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::features::resize::{EncodeOptions, PngCompression, PngFilter, ResizeFilter};
+use crate::features::sniffing::MimeValidation;
+use crate::features::dedup::DedupMode;
+use crate::features::encryption::EncryptionConfig;
+
+/// The configuration file is looked up in the working directory, unless the
+/// `VENNBASE_CONFIG` environment variable points somewhere else.
+pub const DEFAULT_CONFIG_PATH: &str = "./vennbase.json";
+
+/// Server-wide settings.
+///
+/// Every field has a default value, so the configuration file only needs the ones that
+/// should be changed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VennbaseConfig {
+    /// Memory (in bytes) used to keep partition pages in memory
+    pub shared_buffers: usize,
+    /// Records bigger than this (in bytes) are always read from disk, so they don't evict
+    /// all the hot pages from the shared buffers
+    pub max_buffered_record_size: usize,
+    /// Maximum number of records held by all the cached query results
+    pub query_cache_records: usize,
+    /// Memory (in bytes) used to keep resized images
+    pub resize_cache_size: usize,
+    /// Variant profiles, like `"thumb": "200xauto"`. The variants of every image are
+    /// generated when it's saved, and can be fetched with `get <id> @<profile>`
+    pub variants: HashMap<String, String>,
+    /// Algorithm used when `get` requests don't specify a `filter=`
+    pub resize_filter: ResizeFilter,
+    /// Quality of the JPEG images generated by resizing or converting, unless `get`
+    /// requests specify a `quality=`
+    pub jpeg_quality: u8,
+    /// Same as `jpeg_quality`, for WebP images
+    pub webp_quality: u8,
+    pub png_compression: PngCompression,
+    pub png_filter: PngFilter,
+    /// Whether `save` checks that the data looks like its declared mimetype
+    pub mime_validation: MimeValidation,
+    /// What `save` does with data that is already stored in the same partition
+    pub dedup: DedupMode,
+    /// Size (in bytes) of the parts of multi-part uploads. Every part but the last must
+    /// have exactly this size
+    pub upload_chunk_size: u64,
+    /// Mimetypes of the records that are stored compressed, like `text/*` or
+    /// `application/json`
+    pub compression: Vec<String>,
+    /// Key files and mimetypes of the records stored encrypted. Without a key file,
+    /// nothing is encrypted
+    pub encryption: EncryptionConfig,
+}
+
+impl Default for VennbaseConfig {
+    fn default() -> Self {
+        let encoding = EncodeOptions::default();
+        VennbaseConfig {
+            shared_buffers: 64 * 1024 * 1024,
+            max_buffered_record_size: 1024 * 1024,
+            query_cache_records: 65536,
+            resize_cache_size: 32 * 1024 * 1024,
+            variants: HashMap::new(),
+            resize_filter: ResizeFilter::Nearest,
+            jpeg_quality: encoding.jpeg_quality,
+            webp_quality: encoding.webp_quality,
+            png_compression: encoding.png_compression,
+            png_filter: encoding.png_filter,
+            mime_validation: MimeValidation::Off,
+            dedup: DedupMode::Off,
+            upload_chunk_size: 8 * 1024 * 1024,
+            compression: Vec::new(),
+            encryption: EncryptionConfig::default(),
+        }
+    }
+}
+
+impl VennbaseConfig {
+    /// Loads the configuration file, falling back to the defaults if it doesn't exist.
+    pub fn load() -> io::Result<Self> {
+        let path = std::env::var("VENNBASE_CONFIG").unwrap_or(DEFAULT_CONFIG_PATH.into());
+        if !Path::new(&path).exists() {
+            return Ok(VennbaseConfig::default());
+        }
+        println!("Using config from {path:?}");
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        let config: VennbaseConfig = serde_json::from_reader(reader)?;
+        for (name, quality) in [("jpeg_quality", config.jpeg_quality), ("webp_quality", config.webp_quality)] {
+            if !(1..=100).contains(&quality) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{name} must be between 1 and 100, got {quality}")
+                ));
+            }
+        }
+        if config.upload_chunk_size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "upload_chunk_size can't be 0"));
+        }
+        if config.encryption.key_file.is_none() && !config.encryption.previous_key_files.is_empty() {
+            // Data encrypted with the previous keys couldn't be re-encrypted
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "encryption.previous_key_files need a key_file"));
+        }
+        Ok(config)
+    }
+
+    pub fn encoding(&self) -> EncodeOptions {
+        EncodeOptions {
+            jpeg_quality: self.jpeg_quality,
+            webp_quality: self.webp_quality,
+            png_compression: self.png_compression,
+            png_filter: self.png_filter,
+        }
+    }
+}
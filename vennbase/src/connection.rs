@@ -1,11 +1,19 @@
 use std::io::{self, prelude::*, BufReader, BufWriter};
 use std::net::TcpStream;
+use std::sync::Mutex;
 use std::str::FromStr;
 
 use crate::db::partition::StoredRecord;
-use crate::db::types::MimeType;
+use crate::db::types::{ByteRange, MimeType};
 use crate::db::vennbase::Vennbase;
-use crate::features::resize::Dimensions;
+use crate::features::resize::{
+    Dimensions, Gravity, PngCompression, PngFilter, ResizeFilter, ResizeMode, ResizeOptions,
+    is_resizable_format, output_format_from_name, quality_from_str,
+};
+use crate::features::similarity::DEFAULT_SIMILARITY_THRESHOLD;
+use crate::features::sniffing::{SNIFFED_HEAD_LENGTH, detect_mime_type};
+use crate::features::views::ViewError;
+use crate::query::QueryError;
 use crate::utils::reading::read_string_until;
 
 const MAX_REQUEST_QUERY_LENGTH: usize = 1024;
@@ -24,14 +32,18 @@ macro_rules! write_to_socket {
 ///
 /// This function only fail on unrecoverable socket errors. Input/Validation errors doesn't destroy
 /// the communication with the client.
+///
+/// The database is locked while each request is handled, except while the data of new
+/// records is received, so slow clients don't hold up the others.
 #[allow(clippy::unnecessary_unwrap)]
-pub fn handle_connection(stream: &TcpStream, db: &mut Vennbase) -> io::Result<()> {
+pub fn handle_connection(stream: &TcpStream, db_lock: &Mutex<Vennbase>) -> io::Result<()> {
     let mut reader = BufReader::new(stream);
     // Each loop iteration represents a request
     loop {
         let (header, eof) = read_string_until(&mut reader, b'\n', MAX_REQUEST_QUERY_LENGTH)?;
         if eof { break; }
         if header.is_empty() { continue; }
+        let mut db = db_lock.lock().unwrap();
 
         let mut header_iter = header.split(' ');
         let method = header_iter.next().unwrap_or_default();
@@ -40,18 +52,43 @@ pub fn handle_connection(stream: &TcpStream, db: &mut Vennbase) -> io::Result<()
 
         match method {
             "query" => {
+                let mut header_iter = header_iter.peekable();
+                // `chunk=<n>` switches to the streaming response
+                let chunk_size = match header_iter.peek().and_then(|t| t.strip_prefix("chunk=")) {
+                    Some(n) => match n.parse::<usize>() {
+                        Ok(n) if n > 0 => {
+                            header_iter.next();
+                            Some(n)
+                        },
+                        _ => {
+                            write_to_socket!(stream, "ERROR invalid-request 0 chunk size must be a positive number\n")?;
+                            continue;
+                        },
+                    },
+                    None => None,
+                };
                 // rest of the header
-                println!("{header_iter:?}");
                 let header = header_iter.collect::<Vec<_>>().join(" ");
                 let query = header.as_str();
                 println!("{query}");
-                if query.is_empty() {
-                    write_to_socket!(stream, "ERROR 0\n")?;
+
+                if let Some(chunk_size) = chunk_size {
+                    let matches = match db.iter_query_records(query) {
+                        Ok(matches) => matches,
+                        Err(e) => {
+                            println!("Error(query): {:?}", e);
+                            write_to_socket!(stream, "ERROR {e}\n")?;
+                            continue;
+                        },
+                    };
+                    let mut writer = BufWriter::new(stream);
+                    let total = write_query_stream(&mut writer, &db, matches, chunk_size)?;
+                    println!("{total} record(s) streamed.");
                     continue;
                 }
+
                 match db.query_records(query) {
                     Ok(records) => {
-                        dbg!(&query);
                         if records.is_empty() {
                             write_to_socket!(stream, "OK 0\n")?;
                             continue;
@@ -60,64 +97,262 @@ pub fn handle_connection(stream: &TcpStream, db: &mut Vennbase) -> io::Result<()
                         writer.write_all(
                             format!("OK {}\n", records.len()).as_bytes()
                         )?;
-                        for (mimetype, record_id) in records.iter() {
-                            let tags = db.get_tags_for_record(record_id);
-                            writer.write_all(
-                                format!(
-                                    "{record_id:?}\n{mimetype}\n{}\n",
-                                    tags.len()
-                                ).as_bytes()
-                            )?;
-                            if !tags.is_empty() {
-                                writer.write_all(
-                                    format!("{}\n", tags.join("\n")).as_bytes()
-                                )?;
-                            }
-                        }
+                        write_records(&mut writer, &db, records.iter().map(|(m, id)| (m, id)))?;
                         println!("{} record(s) queried.", records.len());
                     },
                     Err(e) => {
                         println!("Error(query): {:?}", e);
-                        write_to_socket!(stream, "ERROR 0\n")?;
+                        write_to_socket!(stream, "ERROR {e}\n")?;
                     },
                 }
             },
+            "explain" => {
+                let header = header_iter.collect::<Vec<_>>().join(" ");
+                let query = header.as_str();
+                match db.explain_query(query) {
+                    Ok(plan) => {
+                        let lines = plan.lines();
+                        write_to_socket!(stream, "OK {}\n{}\n", lines.len(), lines.join("\n"))?;
+                    },
+                    Err(e) => {
+                        println!("Error(explain): {:?}", e);
+                        write_to_socket!(stream, "ERROR {e}\n")?;
+                    },
+                }
+            },
+            "view" => {
+                let action = header_iter.next().unwrap_or_default();
+                match action {
+                    "create" => {
+                        let name = header_iter.next().unwrap_or_default();
+                        let query = header_iter.collect::<Vec<_>>().join(" ");
+                        match db.create_view(name, &query) {
+                            Ok(()) => write_to_socket!(stream, "OK {name}\n")?,
+                            Err(e) => {
+                                println!("Error(view): {:?}", e);
+                                write_to_socket!(stream, "ERROR {e}\n")?;
+                            },
+                        }
+                    },
+                    "drop" => {
+                        let name = header_iter.next().unwrap_or_default();
+                        match db.drop_view(name) {
+                            Ok(()) => write_to_socket!(stream, "OK {name}\n")?,
+                            Err(ViewError::NotFound(_)) => write_to_socket!(stream, "NOT_FOUND 0\n")?,
+                            Err(e) => {
+                                println!("Error(view): {:?}", e);
+                                write_to_socket!(stream, "ERROR {e}\n")?;
+                            },
+                        }
+                    },
+                    "list" => {
+                        let views = db.list_views();
+                        let mut writer = BufWriter::new(stream);
+                        writer.write_all(format!("OK {}\n", views.len()).as_bytes())?;
+                        for (name, query) in views {
+                            writer.write_all(format!("{name} {query}\n").as_bytes())?;
+                        }
+                    },
+                    _ => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 unknown view action '{action}'\n")?;
+                    },
+                }
+            },
+            "compact" => {
+                let reclaimed = db.compact()?;
+                write_to_socket!(stream, "OK {reclaimed}\n")?;
+                println!("Compaction reclaimed {reclaimed} bytes.");
+            },
+            "stats" => {
+                let stats = db.cache_stats();
+                let mut writer = BufWriter::new(stream);
+                writer.write_all(format!("OK {}\n", stats.len()).as_bytes())?;
+                for (name, stats) in stats {
+                    writer.write_all(format!(
+                        "{name} hits={} misses={} hit_rate={:.4} entries={} size={} max_size={}\n",
+                        stats.hits, stats.misses, stats.hit_rate(), stats.entries, stats.size, stats.max_size
+                    ).as_bytes())?;
+                }
+            },
             "get" => {
                 let uuid = match header_iter.next().map(uuid::Uuid::from_str) {
                     Some(Ok(id)) => id,
                     _ => {
-                        write_to_socket!(stream, "ERROR 0\n")?;
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected a record id\n")?;
                         continue;
                     }
                 };
-                let resize_dims: Option<Dimensions> = match header_iter.next().map(Dimensions::from_dim_str) {
-                    Some(Ok(dims)) => Some(dims),
-                    _ => None, // Just ignore invalid dimension specifiers
-                };
+                let mut resize_dims: Option<Dimensions> = None;
+                let mut filter = db.default_resize_filter();
+                let mut mode = ResizeMode::default();
+                let mut gravity = Gravity::default();
+                let mut output = None;
+                let mut encoding = db.default_encoding();
+                // Whether the image has to be re-encoded even if it's not resized
+                let mut reencode = false;
+                let mut strip_exif = false;
+                let mut first_frame = false;
+                let mut if_none_match = None;
+                let mut byte_range = None;
+                let mut variant = None;
+                let mut raw = false;
+                let mut invalid_option = None;
+                for arg in header_iter.by_ref() {
+                    match arg.split_once('=') {
+                        Some(("filter", name)) => match ResizeFilter::from_name(name) {
+                            Ok(name) => filter = name,
+                            Err(_) => invalid_option = Some(arg),
+                        },
+                        Some(("mode", name)) => match ResizeMode::from_name(name) {
+                            Ok(name) => mode = name,
+                            Err(_) => invalid_option = Some(arg),
+                        },
+                        Some(("gravity", name)) => match Gravity::from_name(name) {
+                            Ok(name) => gravity = name,
+                            Err(_) => invalid_option = Some(arg),
+                        },
+                        Some(("quality", quality)) => match quality_from_str(quality) {
+                            Ok(quality) => {
+                                encoding.jpeg_quality = quality;
+                                encoding.webp_quality = quality;
+                                reencode = true;
+                            },
+                            Err(_) => invalid_option = Some(arg),
+                        },
+                        Some(("compression", name)) => match PngCompression::from_name(name) {
+                            Ok(name) => {
+                                encoding.png_compression = name;
+                                reencode = true;
+                            },
+                            Err(_) => invalid_option = Some(arg),
+                        },
+                        Some(("png_filter", name)) => match PngFilter::from_name(name) {
+                            Ok(name) => {
+                                encoding.png_filter = name;
+                                reencode = true;
+                            },
+                            Err(_) => invalid_option = Some(arg),
+                        },
+                        Some(("strip", "exif")) => strip_exif = true,
+                        Some(("raw", "1")) => raw = true,
+                        Some(("if-none-match", etag)) => if_none_match = Some(etag),
+                        Some(("range", range)) => match ByteRange::from_range_str(range) {
+                            Ok(range) => byte_range = Some((range, arg)),
+                            Err(_) => invalid_option = Some(arg),
+                        },
+                        Some(("frame", "first")) => {
+                            first_frame = true;
+                            reencode = true;
+                        },
+                        Some(("as", name)) => match output_format_from_name(name) {
+                            Some(format) => output = Some(Ok(format)),
+                            None => output = Some(Err(name)),
+                        },
+                        Some(_) => invalid_option = Some(arg),
+                        None if arg.starts_with('@') => variant = Some(&arg[1..]),
+                        None => match Dimensions::from_dim_str(arg) {
+                            Ok(dims) => resize_dims = Some(dims),
+                            Err(_) => invalid_option = Some(arg),
+                        },
+                    }
+                }
+                if let Some(arg) = invalid_option {
+                    write_to_socket!(stream, "ERROR invalid-option 0 invalid option '{arg}'\n")?;
+                    continue;
+                }
+                if let Some(Err(name)) = output {
+                    write_to_socket!(stream, "ERROR unsupported-conversion 0 unknown output format '{name}'\n")?;
+                    continue;
+                }
+                let output = output.and_then(Result::ok);
+                if let Some(output) = output {
+                    let source = match (variant, db.find_record_partition(&uuid)) {
+                        (Some(_), _) => Some("variants"),
+                        (None, Some(mimetype)) if !is_resizable_format(mimetype) => Some(mimetype.as_str()),
+                        _ => None,
+                    };
+                    if let Some(source) = source {
+                        write_to_socket!(
+                            stream,
+                            "ERROR unsupported-conversion 0 cannot convert {source} to {}\n",
+                            output.to_mime_type()
+                        )?;
+                        continue;
+                    }
+                    reencode = true;
+                }
+                if reencode {
+                    // Converting or re-encoding without resizing keeps the original dimensions
+                    resize_dims = resize_dims.or(Some(Dimensions::original()));
+                }
+                let resize = resize_dims.map(|dims| ResizeOptions {
+                    dims, filter, mode, gravity, output, encoding, first_frame
+                });
+
+                if let Some(profile) = variant.filter(|profile| !db.has_variant_profile(profile)) {
+                    write_to_socket!(stream, "ERROR unknown-variant 0 variant profile '{profile}' doesn't exist\n")?;
+                    continue;
+                }
+                // Only compressed records are stored differently from how they are served
+                let raw = raw && db.is_record_compressed(&uuid);
+                let etag = db.record_etag(&uuid, variant, &resize, strip_exif, raw);
+                if let (Some(etag), Some(expected)) = (&etag, if_none_match) {
+                    if expected == "*" || expected == etag {
+                        write_to_socket!(stream, "NOT_MODIFIED 0\n")?;
+                        continue;
+                    }
+                }
+                let etag = etag.map(|etag| format!(" {etag}")).unwrap_or_default();
 
                 // When we fetch a record, we get a Take<BufReader<File>>
-                match db.fetch_record_by_id(&uuid, &resize_dims)? {
-                    Some((mimetype, mut record)) => {
+                let record = match variant {
+                    _ if raw => db.fetch_compressed_record(&uuid)?,
+                    Some(profile) => db.fetch_variant(&uuid, profile)?,
+                    // Resized and converted images are re-encoded without any metadata
+                    None if strip_exif && resize.is_none() => db.fetch_record_without_metadata(&uuid)?,
+                    None => db.fetch_record_by_id(&uuid, &resize)?,
+                };
+                match record {
+                    Some((mimetype, record)) => {
+                        // Ranges apply to the bytes that would be served, so resized images
+                        // are ranged after resizing them
+                        let total = record.size();
+                        let (mut record, range) = match byte_range.map(|(range, arg)| (range.resolve(total), arg)) {
+                            None => (record, String::new()),
+                            Some((Some((start, end)), _)) => (
+                                record.into_range(start, end)?,
+                                format!(" range={start}-{end}/{total}")
+                            ),
+                            Some((None, arg)) => {
+                                write_to_socket!(
+                                    stream,
+                                    "ERROR range-not-satisfiable 0 {arg} is outside of the {total} bytes of the record\n"
+                                )?;
+                                continue;
+                            },
+                        };
                         let mut writer = BufWriter::new(stream);
+                        let size = record.size();
+                        let encoding = if raw { " encoding=gzip" } else { "" };
+                        writer.write_all(format!("{} {}{}{}{}\n", mimetype, size, etag, range, encoding).as_bytes())?;
                         match record {
                             StoredRecord::InDiskRecord(ref mut reader) => {
-                                let size = reader.limit() as usize;
-                                let mut buf = [0; 1024];
-                                writer.write_all(format!("{} {}\n", mimetype, size).as_bytes())?;
-                                loop {
-                                    let bytes_read = reader.read(&mut buf)?;
-                                    if bytes_read == 0 { break; }
-                                    writer.write_all(&buf[0..bytes_read])?;
-                                }
-                                println!("{} bytes read.", size);
+                                io::copy(reader, &mut writer)?;
+                            },
+                            StoredRecord::InDiskChunks(ref mut reader) => {
+                                io::copy(reader, &mut writer)?;
+                            },
+                            StoredRecord::Decompressed(ref mut reader) => {
+                                io::copy(reader, &mut writer)?;
+                            },
+                            StoredRecord::Decrypted(ref mut reader) => {
+                                io::copy(reader, &mut writer)?;
                             },
                             StoredRecord::InMemoryRecord(data) => {
-                                let size = data.len();
-                                writer.write_all(format!("{} {}\n", mimetype, size).as_bytes())?;
                                 writer.write_all(data.as_slice())?;
-                                println!("{} bytes read.", size);
                             },
                         }
+                        println!("{} bytes read.", size);
                     },
                     None => {
                         write_to_socket!(stream, "NOT_FOUND 0\n")?;
@@ -126,17 +361,26 @@ pub fn handle_connection(stream: &TcpStream, db: &mut Vennbase) -> io::Result<()
                 }
             },
             "save" => {
-                let mimetype = match header_iter.next().map(MimeType::from) {
-                    Some(Ok(mimetype)) => mimetype,
-                    _ => {
-                        write_to_socket!(stream, "ERROR None\n")?;
+                drop(db);
+                // `save auto` detects the mimetype once the data is read
+                let declared = match header_iter.next() {
+                    Some("auto") => None,
+                    Some(mimetype) => match MimeType::from(mimetype) {
+                        Ok(mimetype) => Some(mimetype),
+                        Err(_) => {
+                            write_to_socket!(stream, "ERROR invalid-mimetype 0 invalid mimetype '{mimetype}'\n")?;
+                            continue;
+                        },
+                    },
+                    None => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected a mimetype\n")?;
                         continue;
-                    }
+                    },
                 };
                 let n = match header_iter.next().map(str::parse::<usize>) {
                     Some(Ok(n)) => n,
                     _ => {
-                        write_to_socket!(stream, "ERROR None\n")?;
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected the number of tags\n")?;
                         continue;
                     },
                 };
@@ -146,28 +390,513 @@ pub fn handle_connection(stream: &TcpStream, db: &mut Vennbase) -> io::Result<()
                     tags.push(tag.to_string());
                 }
 
-                let mut data = Vec::with_capacity(1024);
-                reader.read_to_end(&mut data)?;
+                // Only the first bytes are needed to tell the mimetype
+                let mut head = Vec::with_capacity(1024);
+                (&mut reader).take(SNIFFED_HEAD_LENGTH).read_to_end(&mut head)?;
 
-                let uuid = db.save_record(&mimetype, data.as_slice(), tags)?;
+                let mimetype = match declared.map_or_else(|| MimeType::from(detect_mime_type(&head)), Ok) {
+                    Ok(mimetype) => mimetype,
+                    Err(_) => {
+                        io::copy(&mut reader, &mut io::sink())?;
+                        write_to_socket!(stream, "ERROR invalid-mimetype 0 couldn't detect the mimetype of the data\n")?;
+                        continue;
+                    },
+                };
+                let checked = db_lock.lock().unwrap().validate_record_head(&mimetype, &head);
+                let mimetype = match checked {
+                    Ok(mimetype) => mimetype,
+                    Err(e) => {
+                        io::copy(&mut reader, &mut io::sink())?;
+                        write_to_socket!(stream, "ERROR {e}\n")?;
+                        continue;
+                    },
+                };
+                // Big records are written to the partition while they are read, unless
+                // they are images that must be decoded
+                if head.len() as u64 == SNIFFED_HEAD_LENGTH && !is_resizable_format(&mimetype) {
+                    let encoder = db_lock.lock().unwrap().record_encoder(&mimetype);
+                    let spooled = encoder.spool(&mut head.as_slice().chain(&mut reader))?;
+                    let uuid = db_lock.lock().unwrap().save_spooled_record(&mimetype, spooled, tags)?;
+                    write_to_socket!(stream, "OK {uuid}\n")?;
+                    println!("Streamed record {uuid}");
+                    continue;
+                }
+
+                let mut data = head;
+                reader.read_to_end(&mut data)?;
+                let mut db = db_lock.lock().unwrap();
+                let (mimetype, image) = match db.validate_record(&mimetype, &data) {
+                    Ok(validated) => validated,
+                    Err(e) => {
+                        write_to_socket!(stream, "ERROR {e}\n")?;
+                        continue;
+                    },
+                };
+                let uuid = db.save_decoded_record(&mimetype, data.as_slice(), image.as_ref(), tags)?;
                 write_to_socket!(stream, "OK {uuid}\n")?;
                 println!("Saving record {uuid} with len {:#?}", data.len());
             },
             "del" => {
-                // TODO: read id
-                db.delete_record("");
+                let uuid = match header_iter.next().map(uuid::Uuid::from_str) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected a record id\n")?;
+                        continue;
+                    }
+                };
+                if db.delete_record(&uuid)? {
+                    write_to_socket!(stream, "OK {uuid}\n")?;
+                    println!("Record {uuid} deleted.");
+                }
+                else {
+                    write_to_socket!(stream, "NOT_FOUND 0\n")?;
+                    println!("Record not found.");
+                }
+            },
+            "meta" => {
+                let uuid = match header_iter.next().map(uuid::Uuid::from_str) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected a record id\n")?;
+                        continue;
+                    }
+                };
+                let Some((mimetype, size, metadata)) = db.record_information(&uuid) else {
+                    write_to_socket!(stream, "NOT_FOUND 0\n")?;
+                    continue;
+                };
+                let tags = db.get_tags_for_record(&uuid);
+                let mut properties = vec![format!("size {size}")];
+                if let Some(metadata) = metadata {
+                    properties.push(format!("width {}", metadata.width));
+                    properties.push(format!("height {}", metadata.height));
+                    properties.push(format!("color_type {}", metadata.color_type));
+                    properties.push(format!("animated {}", metadata.animated));
+                    properties.push(format!("orientation {}", metadata.orientation()));
+                    if let Some(dhash) = metadata.dhash {
+                        properties.push(format!("dhash {dhash:016x}"));
+                    }
+                }
+                let mut writer = BufWriter::new(stream);
+                writer.write_all(format!("OK {mimetype} {} {}\n", tags.len(), properties.len()).as_bytes())?;
+                for line in tags.iter().copied().chain(properties.iter().map(String::as_str)) {
+                    writer.write_all(format!("{line}\n").as_bytes())?;
+                }
+            },
+            "similar" => {
+                let uuid = match header_iter.next().map(uuid::Uuid::from_str) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected a record id\n")?;
+                        continue;
+                    }
+                };
+                let threshold = match header_iter.next().map(|arg| arg.strip_prefix("threshold=")) {
+                    None => DEFAULT_SIMILARITY_THRESHOLD,
+                    Some(Some(n)) => match n.parse::<u32>() {
+                        Ok(n) if n <= 64 => n,
+                        _ => {
+                            write_to_socket!(stream, "ERROR invalid-request 0 threshold must be a number up to 64\n")?;
+                            continue;
+                        },
+                    },
+                    Some(None) => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected threshold=<n>\n")?;
+                        continue;
+                    },
+                };
+                if db.find_record_partition(&uuid).is_none() {
+                    write_to_socket!(stream, "NOT_FOUND 0\n")?;
+                    continue;
+                }
+                let Some(records) = db.similar_records(&uuid, threshold) else {
+                    write_to_socket!(stream, "ERROR not-an-image 0 record {uuid} has no perceptual hash\n")?;
+                    continue;
+                };
+                let mut writer = BufWriter::new(stream);
+                writer.write_all(format!("OK {}\n", records.len()).as_bytes())?;
+                write_records(&mut writer, &db, records.iter().map(|(m, id)| (*m, id)))?;
+                println!("{} similar record(s) found.", records.len());
+            },
+            "tag" => {
+                let action = header_iter.next().unwrap_or_default();
+                let uuid = header_iter.next().map(uuid::Uuid::from_str);
+                let tag = header_iter.next().unwrap_or_default();
+                let uuid = match uuid {
+                    Some(Ok(id)) if !tag.is_empty() && tag.len() <= MAX_RECORD_TAG_LENGTH => id,
+                    _ => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected a record id and a tag\n")?;
+                        continue;
+                    }
+                };
+                let found = match action {
+                    "add" => db.add_tag(&uuid, tag),
+                    "remove" => db.remove_tag(&uuid, tag),
+                    _ => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 unknown tag action '{action}'\n")?;
+                        continue;
+                    }
+                };
+                if found {
+                    write_to_socket!(stream, "OK {uuid}\n")?;
+                }
+                else {
+                    write_to_socket!(stream, "NOT_FOUND 0\n")?;
+                }
+            },
+            "upload" => {
+                let action = header_iter.next().unwrap_or_default();
+                if action == "begin" {
+                    // Same header as `save`
+                    let mimetype = match header_iter.next() {
+                        Some("auto") => None,
+                        Some(mimetype) => match MimeType::from(mimetype) {
+                            Ok(mimetype) => Some(mimetype),
+                            Err(_) => {
+                                write_to_socket!(stream, "ERROR invalid-mimetype 0 invalid mimetype '{mimetype}'\n")?;
+                                continue;
+                            },
+                        },
+                        None => {
+                            write_to_socket!(stream, "ERROR invalid-request 0 expected a mimetype\n")?;
+                            continue;
+                        },
+                    };
+                    let n = match header_iter.next().map(str::parse::<usize>) {
+                        Some(Ok(n)) => n,
+                        _ => {
+                            write_to_socket!(stream, "ERROR invalid-request 0 expected the number of tags\n")?;
+                            continue;
+                        },
+                    };
+                    let mut tags = vec![];
+                    for _ in 0..n {
+                        let (tag, _) = read_string_until(&mut reader, b'\n', MAX_RECORD_TAG_LENGTH)?;
+                        tags.push(tag.to_string());
+                    }
+                    let id = db.begin_upload(mimetype.as_ref(), tags)?;
+                    write_to_socket!(stream, "OK {id}\n")?;
+                    println!("Upload {id} started.");
+                    continue;
+                }
+
+                let id = match header_iter.next().map(uuid::Uuid::from_str) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected an upload id\n")?;
+                        continue;
+                    }
+                };
+                match action {
+                    "part" => {
+                        let part = match header_iter.next().map(str::parse::<u32>) {
+                            Some(Ok(part)) => part,
+                            _ => {
+                                write_to_socket!(stream, "ERROR invalid-request 0 expected a part number\n")?;
+                                continue;
+                            },
+                        };
+                        let encoder = db.upload_encoder(&id, part);
+                        drop(db);
+                        let stored = match encoder {
+                            Ok(encoder) => {
+                                let spooled = encoder.spool(&mut reader)?;
+                                db_lock.lock().unwrap().store_upload_part(&id, part, spooled)
+                            },
+                            Err(e) => Err(e),
+                        };
+                        match stored {
+                            Ok(size) => {
+                                write_to_socket!(stream, "OK {size}\n")?;
+                                println!("Part {part} of upload {id} with len {size}");
+                            },
+                            Err(e) => {
+                                io::copy(&mut reader, &mut io::sink())?;
+                                write_to_socket!(stream, "ERROR {e}\n")?;
+                            },
+                        }
+                    },
+                    "status" => match db.upload_status(&id) {
+                        Ok(parts) => {
+                            let mut writer = BufWriter::new(stream);
+                            writer.write_all(format!("OK {}\n", parts.len()).as_bytes())?;
+                            for (part, size) in parts {
+                                writer.write_all(format!("{part} {size}\n").as_bytes())?;
+                            }
+                        },
+                        Err(e) => write_to_socket!(stream, "ERROR {e}\n")?,
+                    },
+                    "commit" => match db.commit_upload(&id) {
+                        Ok(uuid) => {
+                            write_to_socket!(stream, "OK {uuid}\n")?;
+                            println!("Upload {id} saved as record {uuid}");
+                        },
+                        Err(e) => write_to_socket!(stream, "ERROR {e}\n")?,
+                    },
+                    "abort" => match db.abort_upload(&id) {
+                        Ok(()) => write_to_socket!(stream, "OK {id}\n")?,
+                        Err(e) => write_to_socket!(stream, "ERROR {e}\n")?,
+                    },
+                    _ => write_to_socket!(stream, "ERROR invalid-request 0 unknown upload action '{action}'\n")?,
+                }
             },
             "replace" => {
+                let uuid = match header_iter.next().map(uuid::Uuid::from_str) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        write_to_socket!(stream, "ERROR invalid-request 0 expected a record id\n")?;
+                        continue;
+                    }
+                };
+                drop(db);
                 let mut data = Vec::with_capacity(512);
-                // TODO: read id
                 reader.read_to_end(&mut data)?;
-                db.replace_record("", data.as_slice());
+                let mut db = db_lock.lock().unwrap();
+                let image = match db.validate_replacement(&uuid, &data) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        write_to_socket!(stream, "ERROR {e}\n")?;
+                        continue;
+                    },
+                };
+                if db.replace_decoded_record(&uuid, data.as_slice(), image.as_ref())? {
+                    write_to_socket!(stream, "OK {uuid}\n")?;
+                    println!("Replacing record {uuid} with len {:#?}", data.len());
+                }
+                else {
+                    write_to_socket!(stream, "NOT_FOUND 0\n")?;
+                    println!("Record not found.");
+                }
             },
             other => {
-                write_to_socket!(stream, "Unknown method: '{}'\n", other)?;
+                write_to_socket!(stream, "ERROR unknown-method 0 unknown method '{other}'\n")?;
             }
         }
     }
 
     Ok(())
 }
+
+/// Writes the id, mimetype and tags of each record, as expected by `query` responses.
+fn write_records<'a, W: Write>(
+    writer: &mut W,
+    db: &Vennbase,
+    records: impl IntoIterator<Item=(&'a MimeType, &'a uuid::Uuid)>
+) -> io::Result<()> {
+    for (mimetype, record_id) in records {
+        let tags = db.get_tags_for_record(record_id);
+        writer.write_all(
+            format!(
+                "{record_id:?}\n{mimetype}\n{}\n",
+                tags.len()
+            ).as_bytes()
+        )?;
+        if !tags.is_empty() {
+            writer.write_all(
+                format!("{}\n", tags.join("\n")).as_bytes()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the `STREAM` response of a query, in `CHUNK` frames of at most `chunk_size` records
+/// followed by `END <total>`. An error ends the stream right away with an `ERROR` line instead,
+/// dropping the matches that were not sent yet. Returns the number of records sent.
+fn write_query_stream<'a, W: Write>(
+    writer: &mut W,
+    db: &Vennbase,
+    matches: impl IntoIterator<Item=Result<(&'a MimeType, &'a uuid::Uuid), QueryError>>,
+    chunk_size: usize
+) -> io::Result<usize> {
+    writer.write_all(b"STREAM\n")?;
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut total = 0;
+    for record in matches {
+        match record {
+            Ok(record) => chunk.push(record),
+            Err(e) => {
+                println!("Error(query): {:?}", e);
+                writer.write_all(format!("ERROR {e}\n").as_bytes())?;
+                writer.flush()?;
+                return Ok(total);
+            },
+        }
+        if chunk.len() == chunk_size {
+            write_records_chunk(writer, db, &chunk)?;
+            total += chunk.len();
+            chunk.clear();
+        }
+    }
+    if !chunk.is_empty() {
+        write_records_chunk(writer, db, &chunk)?;
+        total += chunk.len();
+    }
+    writer.write_all(format!("END {total}\n").as_bytes())?;
+    Ok(total)
+}
+
+/// Writes a `CHUNK <n>` frame of a streamed query and flushes it to the client.
+fn write_records_chunk<W: Write>(
+    writer: &mut W,
+    db: &Vennbase,
+    records: &[(&MimeType, &uuid::Uuid)]
+) -> io::Result<()> {
+    writer.write_all(format!("CHUNK {}\n", records.len()).as_bytes())?;
+    write_records(writer, db, records.iter().copied())?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Shutdown, TcpListener};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config::VennbaseConfig;
+    use crate::db::vennbase::testing::{png, temp_db, temp_db_with};
+    use crate::query::QueryErrorKind;
+
+    /// Sends a request to `handle_connection` through a local socket and returns the response.
+    fn request(db: &Mutex<Vennbase>, request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let request = request.to_owned();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            response
+        });
+        let (stream, _) = listener.accept().unwrap();
+        handle_connection(&stream, db).unwrap();
+        drop(stream);
+        String::from_utf8_lossy(&client.join().unwrap()).into_owned()
+    }
+
+    #[test]
+    fn streamed_queries_are_sent_in_chunks() -> io::Result<()> {
+        let (mut db, path) = temp_db()?;
+        let mimetype = MimeType::from("text/plain").unwrap();
+        for data in ["a", "b", "c"] {
+            db.save_record(&mimetype, data.as_bytes(), vec!["letter".to_owned()])?;
+        }
+        let db = Mutex::new(db);
+
+        let response = request(&db, "query chunk=2 tag:letter\n");
+        let frames: Vec<_> = response.lines()
+            .filter(|line| ["STREAM", "CHUNK", "END"].iter().any(|frame| line.starts_with(frame)))
+            .collect();
+        assert_eq!(frames, ["STREAM", "CHUNK 2", "CHUNK 1", "END 3"]);
+        assert_eq!(request(&db, "query chunk=2 tag:missing\n"), "STREAM\nEND 0\n");
+
+        std::fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn etags_identify_each_representation() -> io::Result<()> {
+        let (mut db, path) = temp_db()?;
+        let id = db.save_record(&MimeType::from("image/png").unwrap(), &png(40, 20), vec![])?;
+        let db = Mutex::new(db);
+        let etag = |db: &Mutex<Vennbase>, options: &str| {
+            let response = request(db, &format!("get {id}{options}\n"));
+            let header = response.lines().next().unwrap().to_owned();
+            header.split(' ').nth(2).unwrap().to_owned()
+        };
+
+        let original = etag(&db, "");
+        assert_eq!(etag(&db, ""), original);
+        let resized = etag(&db, " 10xauto");
+        assert_eq!(etag(&db, " 10xauto"), resized);
+        assert_ne!(resized, original);
+        assert_ne!(etag(&db, " 10xauto filter=lanczos3"), resized);
+        assert_ne!(etag(&db, " strip=exif"), original);
+
+        assert_eq!(request(&db, &format!("get {id} if-none-match={original}\n")), "NOT_MODIFIED 0\n");
+        assert_eq!(request(&db, &format!("get {id} 10xauto if-none-match={resized}\n")), "NOT_MODIFIED 0\n");
+        let response = request(&db, &format!("get {id} 10xauto if-none-match={original}\n"));
+        assert!(response.starts_with("image/png "));
+
+        std::fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn raw_records_have_their_own_etag() -> io::Result<()> {
+        let config = VennbaseConfig { compression: vec!["text/plain".to_owned()], ..VennbaseConfig::default() };
+        let (mut db, path) = temp_db_with(&config)?;
+        let id = db.save_record(&MimeType::from("text/plain").unwrap(), "compress me ".repeat(100).as_bytes(), vec![])?;
+        let db = Mutex::new(db);
+        let header = |db: &Mutex<Vennbase>, options: &str| {
+            request(db, &format!("get {id}{options}\n")).lines().next().unwrap().to_owned()
+        };
+
+        let raw = header(&db, " raw=1");
+        assert!(raw.ends_with(" encoding=gzip"));
+        assert_ne!(raw.split(' ').nth(2), header(&db, "").split(' ').nth(2));
+        // The options that change other representations don't change the raw one
+        assert_eq!(header(&db, " 100x100 raw=1"), raw);
+        assert_eq!(header(&db, " strip=exif raw=1"), raw);
+
+        std::fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn query_streams_end_with_end_or_error() -> io::Result<()> {
+        let (db, path) = temp_db()?;
+        let mimetype = MimeType::from("text/plain").unwrap();
+        let ids = [uuid::Uuid::new_v4(), uuid::Uuid::new_v4(), uuid::Uuid::new_v4()];
+        let record = |id: &uuid::Uuid| format!("{id}\ntext/plain\n0\n");
+
+        let mut out = Vec::new();
+        assert_eq!(write_query_stream(&mut out, &db, ids.iter().map(|id| Ok((&mimetype, id))), 2)?, 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("STREAM\nCHUNK 2\n{}{}CHUNK 1\n{}END 3\n", record(&ids[0]), record(&ids[1]), record(&ids[2]))
+        );
+
+        // Nothing follows the error, not even the matches that were waiting for their chunk
+        let error = QueryError { kind: QueryErrorKind::Evaluation, offset: 0, message: "failed".to_owned() };
+        let matches = ids.iter().map(|id| Ok((&mimetype, id))).chain([Err(error), Ok((&mimetype, &ids[0]))]);
+        let mut out = Vec::new();
+        assert_eq!(write_query_stream(&mut out, &db, matches, 2)?, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("STREAM\nCHUNK 2\n{}{}ERROR evaluation 0 failed\n", record(&ids[0]), record(&ids[1]))
+        );
+
+        std::fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn other_requests_go_on_while_a_record_is_received() -> io::Result<()> {
+        let (db, path) = temp_db()?;
+        let db = Arc::new(Mutex::new(db));
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+        let server = {
+            let db = Arc::clone(&db);
+            std::thread::spawn(move || handle_connection(&stream, &db))
+        };
+
+        // Bigger than the sniffed head, so it's streamed
+        let data = vec![b'a'; 2 * SNIFFED_HEAD_LENGTH as usize];
+        client.write_all(b"save text/plain 0\n")?;
+        client.write_all(&data[..SNIFFED_HEAD_LENGTH as usize + 10])?;
+        assert_eq!(request(&db, "query chunk=2 tag:missing\n"), "STREAM\nEND 0\n");
+        client.write_all(&data[SNIFFED_HEAD_LENGTH as usize + 10..])?;
+        client.shutdown(Shutdown::Write)?;
+        let mut response = String::new();
+        client.read_to_string(&mut response)?;
+        server.join().unwrap()?;
+
+        let id = uuid::Uuid::from_str(response.trim().strip_prefix("OK ").unwrap()).unwrap();
+        let (_, size, _) = db.lock().unwrap().record_information(&id).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(std::fs::read_dir(path.join(".spool"))?.count(), 0);
+
+        std::fs::remove_dir_all(path)
+    }
+}
@@ -1,3 +1,4 @@
 pub mod vennbase;
 pub mod partition;
 pub mod types;
+pub mod spool;
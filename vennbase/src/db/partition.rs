@@ -1,22 +1,276 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::{self, prelude::*, BufReader, BufWriter, SeekFrom};
 use std::fs::{File, OpenOptions};
+use std::sync::Arc;
 
 use crate::{read_venn_timestamp, read_u64, read_n_bytes};
 use crate::db::types::VennTimestamp;
+use crate::features::compression::DecompressedRecord;
+use crate::features::encryption::{DecryptedRecord, EncryptionKeys, plaintext_size};
+
+/// How the data of a record is stored in the partition file. The partition only keeps
+/// track of it, the data is encoded and decoded by the database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordEncoding {
+    /// The data is a manifest of the chunks that hold the actual data, see
+    /// `Partition::push_chunked_record`
+    pub chunked: bool,
+    /// The data is compressed, see `features::compression`
+    pub compressed: bool,
+    /// The data is encrypted, see `features::encryption`. Compressed records are
+    /// compressed before being encrypted
+    pub encrypted: bool,
+}
+
+impl RecordEncoding {
+    fn from_flags(flags: u8) -> Self {
+        RecordEncoding {
+            chunked: flags & RECORD_CHUNKED_FLAG != 0,
+            compressed: flags & RECORD_COMPRESSED_FLAG != 0,
+            encrypted: flags & RECORD_ENCRYPTED_FLAG != 0,
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.chunked { flags |= RECORD_CHUNKED_FLAG; }
+        if self.compressed { flags |= RECORD_COMPRESSED_FLAG; }
+        if self.encrypted { flags |= RECORD_ENCRYPTED_FLAG; }
+        flags
+    }
+}
 
 #[derive(Debug)]
 pub struct RecordInformation {
     is_active: bool,
+    is_alias: bool,
+    encoding: RecordEncoding,
+    header_start: u64,
     start: u64,
     size: u64,
 }
 
+impl RecordInformation {
+    pub fn is_active(&self) -> bool {
+        self.is_active
+    }
+
+    /// Whether the record shares the data of another record, see `Partition::push_alias`
+    pub fn is_alias(&self) -> bool {
+        self.is_alias
+    }
+
+    /// Whether the record data is a manifest of the chunks that hold the actual data, see
+    /// `Partition::push_chunked_record`
+    pub fn is_chunked(&self) -> bool {
+        self.encoding.chunked
+    }
+
+    pub fn encoding(&self) -> RecordEncoding {
+        self.encoding
+    }
+
+    /// Offset of the record data in the partition file. For aliases, this is the data
+    /// of the record they point to
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Offset of the record header in the partition file
+    pub fn header_start(&self) -> u64 {
+        self.header_start
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of bytes the record takes in the partition file, header included.
+    pub fn stored_size(&self) -> u64 {
+        RECORD_HEADER_SIZE_BYTES + if self.is_alias { ALIAS_PAYLOAD_SIZE_BYTES } else { self.size }
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.is_active { flags |= RECORD_ACTIVE_FLAG; }
+        if self.is_alias { flags |= RECORD_ALIAS_FLAG; }
+        flags | self.encoding.flags()
+    }
+}
+
 #[derive(Debug)]
 pub enum StoredRecord {
     InDiskRecord(io::Take<BufReader<File>>),
     InMemoryRecord(Vec<u8>),
+    InDiskChunks(ChunksReader),
+    Decompressed(DecompressedRecord),
+    Decrypted(DecryptedRecord),
+}
+
+impl StoredRecord {
+    /// Length of the record data, in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            StoredRecord::InDiskRecord(reader) => reader.limit(),
+            StoredRecord::InMemoryRecord(data) => data.len() as u64,
+            StoredRecord::InDiskChunks(reader) => reader.size(),
+            StoredRecord::Decompressed(record) => record.size(),
+            StoredRecord::Decrypted(record) => record.size(),
+        }
+    }
+
+    /// Narrows the record to the bytes from `start` to `end`, both included.
+    ///
+    /// Caller must ensure that the range is within the record.
+    pub fn into_range(self, start: u64, end: u64) -> io::Result<StoredRecord> {
+        match self {
+            StoredRecord::InDiskRecord(mut reader) => {
+                reader.get_mut().seek_relative(start as i64)?;
+                reader.set_limit(end - start + 1);
+                Ok(StoredRecord::InDiskRecord(reader))
+            },
+            StoredRecord::InMemoryRecord(mut data) => {
+                data.truncate(end as usize + 1);
+                data.drain(..start as usize);
+                Ok(StoredRecord::InMemoryRecord(data))
+            },
+            StoredRecord::InDiskChunks(reader) => Ok(StoredRecord::InDiskChunks(reader.into_range(start, end)?)),
+            StoredRecord::Decompressed(record) => Ok(StoredRecord::Decompressed(record.into_range(start, end)?)),
+            StoredRecord::Decrypted(record) => Ok(StoredRecord::Decrypted(record.into_range(start, end)?)),
+        }
+    }
+}
+
+impl Read for StoredRecord {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            StoredRecord::InDiskRecord(reader) => reader.read(buf),
+            StoredRecord::InMemoryRecord(data) => {
+                let len = buf.len().min(data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                data.drain(..len);
+                Ok(len)
+            },
+            StoredRecord::InDiskChunks(reader) => reader.read(buf),
+            StoredRecord::Decompressed(record) => record.read(buf),
+            StoredRecord::Decrypted(record) => record.read(buf),
+        }
+    }
+}
+
+/// Where a chunk of a chunked record is stored in the partition file.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk {
+    pub id: uuid::Uuid,
+    pub start: u64,
+    /// Length of the chunk in the partition file
+    pub size: u64,
+    pub encrypted: bool,
+}
+
+impl Chunk {
+    /// Length of the data of the chunk, once decrypted.
+    fn data_size(&self) -> u64 {
+        match self.encrypted {
+            true => plaintext_size(self.size).unwrap_or(0),
+            false => self.size,
+        }
+    }
+}
+
+/// Reads the data of a chunked record, chunk after chunk.
+#[derive(Debug)]
+pub struct ChunksReader {
+    file_path: PathBuf,
+    keys: Option<Arc<EncryptionKeys>>,
+    // Chunks not yet read, the last one first, with the `(offset, length)` of their data
+    // that is read
+    chunks: Vec<(Chunk, u64, u64)>,
+    current: Option<Box<StoredRecord>>,
+}
+
+impl ChunksReader {
+    /// Reads the given chunks of a partition file, in order. Encrypted chunks are
+    /// decrypted with `keys`.
+    pub fn new(file_path: &Path, chunks: Vec<Chunk>, keys: Option<Arc<EncryptionKeys>>) -> io::Result<Self> {
+        if chunks.iter().any(|chunk| chunk.encrypted) && keys.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Encrypted chunks need an encryption key"));
+        }
+        let chunks = chunks.into_iter().rev().map(|chunk| (chunk, 0, chunk.data_size())).collect();
+        Ok(ChunksReader { file_path: file_path.to_owned(), keys, chunks, current: None })
+    }
+
+    pub fn size(&self) -> u64 {
+        let current = self.current.as_ref().map_or(0, |record| record.size());
+        current + self.chunks.iter().map(|(_, _, len)| len).sum::<u64>()
+    }
+
+    /// Narrows the reader to the bytes from `start` to `end`, both included.
+    fn into_range(mut self, start: u64, end: u64) -> io::Result<Self> {
+        let mut offset = 0;
+        let current = match self.current.take() {
+            Some(record) => {
+                offset = record.size();
+                match start < offset {
+                    true => Some(Box::new(record.into_range(start, end.min(offset - 1))?)),
+                    false => None,
+                }
+            },
+            None => None,
+        };
+        let mut chunks = Vec::new();
+        for (chunk, from, len) in self.chunks.into_iter().rev() {
+            // Part of the range within this chunk, end excluded
+            let (range_start, range_end) = (start.max(offset), (end + 1).min(offset + len));
+            if range_start < range_end {
+                chunks.push((chunk, from + range_start - offset, range_end - range_start));
+            }
+            offset += len;
+        }
+        chunks.reverse();
+        Ok(ChunksReader { file_path: self.file_path, keys: self.keys, chunks, current })
+    }
+
+    fn open_chunk(&self, chunk: &Chunk, from: u64, len: u64) -> io::Result<StoredRecord> {
+        let mut reader = BufReader::new(File::open(&self.file_path)?);
+        reader.seek(SeekFrom::Start(chunk.start))?;
+        let mut record = StoredRecord::InDiskRecord(reader.take(chunk.size));
+        if let Some(keys) = self.keys.as_ref().filter(|_| chunk.encrypted) {
+            record = StoredRecord::Decrypted(keys.decrypt_record(&chunk.id, record)?);
+        }
+        record.into_range(from, from + len - 1)
+    }
+}
+
+impl Read for ChunksReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(record) = self.current.as_mut() {
+                let read = record.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+                if record.size() > 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated chunk"));
+                }
+            }
+            let Some((chunk, from, len)) = self.chunks.pop() else {
+                return Ok(0);
+            };
+            self.current = match len {
+                0 => None,
+                _ => Some(Box::new(self.open_chunk(&chunk, from, len)?)),
+            };
+        }
+    }
+}
+
+/// Drops the last record of a partition file, which was being written when the process
+/// stopped.
+fn truncate_unfinished_record(file_path: &Path, header_start: u64) -> io::Result<()> {
+    println!("  truncating an unfinished record at byte {header_start}");
+    OpenOptions::new().write(true).open(file_path)?.set_len(header_start)
 }
 
 // Each partition contains multiple files of the same type
@@ -24,10 +278,9 @@ pub enum StoredRecord {
 pub struct Partition {
     file_path: PathBuf,
     records: HashMap<uuid::Uuid, RecordInformation>,
-    #[allow(dead_code)]
     created_at: VennTimestamp,
     #[allow(dead_code)]
-    last_compaction: VennTimestamp, // TODO: implement compaction
+    last_compaction: VennTimestamp,
     next_start: u64,
 }
 
@@ -48,6 +301,18 @@ const RECORD_HEADER_SIZE_BYTES: u64 =
     RECORD_ID_SIZE_BYTES +
     RECORD_DATA_LENGTH_SIZE_BYTES;
 
+const RECORD_ACTIVE_FLAG: u8 = 0b10000000;
+const RECORD_ALIAS_FLAG: u8 = 0b01000000;
+const RECORD_CHUNKED_FLAG: u8 = 0b00100000;
+const RECORD_COMPRESSED_FLAG: u8 = 0b00010000;
+const RECORD_ENCRYPTED_FLAG: u8 = 0b00001000;
+
+// Aliases store the offset and length of the data they point to
+const ALIAS_PAYLOAD_SIZE_BYTES: u64 = 16;
+// Length of the records being streamed, until their actual length is known. It's
+// always past the end of the file, so unfinished records are found when it's loaded
+const PENDING_RECORD_SIZE: u64 = u64::MAX;
+
 impl Partition {
     /// Loads the partition data from an existing file_path.
     ///
@@ -60,11 +325,12 @@ impl Partition {
 
         if !file_path.is_file() {
             return Err(
-                io::Error::new(io::ErrorKind::Other, "Partitions can only be files")
+                io::Error::other("Partitions can only be files")
             )
         }
 
         let file = File::open(&file_path)?;
+        let file_len = file.metadata()?.len();
         let mut reader = BufReader::with_capacity(BUFFREADER_CAPACITY, file);
 
         println!("  from {file_path:?}");
@@ -88,23 +354,37 @@ impl Partition {
                 Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
                 Err(err) => return Err(err)
             }
-            let is_active = flags[0] & 0b10000000 != 0;
+            let header_start = next_record_start;
+            if file_len - header_start < RECORD_HEADER_SIZE_BYTES {
+                truncate_unfinished_record(&file_path, header_start)?;
+                break;
+            }
+            let is_active = flags[0] & RECORD_ACTIVE_FLAG != 0;
+            let is_alias = flags[0] & RECORD_ALIAS_FLAG != 0;
+            let encoding = RecordEncoding::from_flags(flags[0]);
             let record_id = uuid::Uuid::from_bytes(
                 read_n_bytes!(&mut reader, RECORD_ID_SIZE_BYTES as usize)?
             );
             let record_size = read_u64!(&mut reader)?;
+            // Records are appended, so only the last one can be cut short by a crash
+            if record_size > file_len - header_start - RECORD_HEADER_SIZE_BYTES {
+                truncate_unfinished_record(&file_path, header_start)?;
+                break;
+            }
 
             next_record_start += RECORD_HEADER_SIZE_BYTES;
+            let (start, size) = if is_alias {
+                (read_u64!(&mut reader)?, read_u64!(&mut reader)?)
+            }
+            else {
+                // Skip the {record_size} bytes of data
+                reader.seek(SeekFrom::Current(record_size as i64))?;
+                (next_record_start, record_size)
+            };
             records.insert(
                 record_id,
-                RecordInformation {
-                    is_active,
-                    start: next_record_start,
-                    size: record_size
-                }
+                RecordInformation { is_active, is_alias, encoding, header_start, start, size }
             );
-            // Skip the {record_size} bytes of data
-            reader.seek(SeekFrom::Current(record_size as i64))?;
             next_record_start += record_size;
         }
         println!("  with {} record(s)", records.len());
@@ -119,6 +399,47 @@ impl Partition {
         })
     }
 
+    /// Creates a new empty partition file and returns the partition.
+    ///
+    /// Caller should ensure that the partition does not exist yet, or the whole file will be
+    /// truncated.
+    pub fn create(file_path: PathBuf) -> io::Result<Self> {
+        // File creation is done with `write: true`, `create: true`, `truncate: true`
+        // So the only error we can get is either a permission error, or to a database
+        // doesn't exist error. Both are fatal.
+        let file = match File::create(&file_path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => todo!(),
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => todo!(),
+            Err(_) => unreachable!()
+        };
+        let mut writer = BufWriter::new(file);
+
+        let created_at = VennTimestamp::now();
+        let last_compaction = VennTimestamp::now();
+
+        // Not be able to write to the partition is considered fatal
+        writer.write_all(created_at.0.to_le_bytes().as_slice())?;
+        writer.write_all(last_compaction.0.to_le_bytes().as_slice())?;
+
+        Ok(Partition::new(
+            file_path,
+            HashMap::new(),
+            created_at,
+            last_compaction
+        ))
+    }
+
+    /// Loads the partition at `file_path`, creating it if it doesn't exist.
+    pub fn open_or_create(file_path: PathBuf) -> io::Result<Self> {
+        if file_path.exists() {
+            Partition::from_file(file_path)
+        }
+        else {
+            Partition::create(file_path)
+        }
+    }
+
     /// Returns the number of records in the partition.
     ///
     /// This considers both active and inactive records.
@@ -145,38 +466,201 @@ impl Partition {
     }
 
     pub fn push_record(&mut self, data: &[u8]) -> io::Result<uuid::Uuid> {
-        let uuid = uuid::Uuid::new_v4();
+        self.push_record_with_id(uuid::Uuid::new_v4(), data)
+    }
+
+    /// Appends a record with a known id.
+    ///
+    /// If an active record with the same id exists, it must be deleted first: when the
+    /// partition is loaded, the last record with a given id wins.
+    pub fn push_record_with_id(&mut self, uuid: uuid::Uuid, data: &[u8]) -> io::Result<uuid::Uuid> {
+        self.push_record_with_encoding(uuid, data, RecordEncoding::default())
+    }
+
+    /// Appends a record whose data is a manifest of chunks stored somewhere else.
+    ///
+    /// The partition doesn't interpret the manifest, it only flags the record as chunked.
+    pub fn push_chunked_record(&mut self, uuid: uuid::Uuid, manifest: &[u8]) -> io::Result<uuid::Uuid> {
+        self.push_record_with_encoding(uuid, manifest, RecordEncoding { chunked: true, ..Default::default() })
+    }
+
+    /// Appends a record whose data is stored encoded, like `push_record_with_id`.
+    pub fn push_record_with_encoding(
+        &mut self,
+        uuid: uuid::Uuid,
+        data: &[u8],
+        encoding: RecordEncoding
+    ) -> io::Result<uuid::Uuid> {
         // FIXME: should we move the writer to the struct itself?
         let file = OpenOptions::new()
             .append(true)
             .open(&self.file_path)?;
 
+        let record_info = RecordInformation {
+            is_active: true,
+            is_alias: false,
+            encoding,
+            header_start: self.next_start - RECORD_HEADER_SIZE_BYTES,
+            start: self.next_start,
+            size: data.len() as u64
+        };
         let mut writer = BufWriter::new(file);
-        writer.write_all(&[1 << 7])?;
+        writer.write_all(&[record_info.flags()])?;
         writer.write_all(uuid.as_bytes())?;
         writer.write_all((data.len() as u64).to_le_bytes().as_slice())?;
         writer.write_all(data)?;
 
+        self.records.insert(uuid, record_info);
+        self.next_start += data.len() as u64 + RECORD_HEADER_SIZE_BYTES;
+
+        Ok(uuid)
+    }
+
+    /// Appends a record while its data is being read, so it's never held in memory.
+    ///
+    /// The length of the data is unknown until the reader is exhausted, so the header is
+    /// written with a placeholder and patched afterwards. If reading fails, the partition
+    /// file is truncated back to its previous length, and if the process stops before the
+    /// record is finished, it's truncated by `from_file`.
+    pub fn push_record_from_reader(
+        &mut self,
+        uuid: uuid::Uuid,
+        reader: &mut impl Read,
+        encoding: RecordEncoding
+    ) -> io::Result<u64> {
+        let header_start = self.next_start - RECORD_HEADER_SIZE_BYTES;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.file_path)?;
+        file.seek(SeekFrom::Start(header_start))?;
+
+        let mut copy = |file: &mut File| -> io::Result<u64> {
+            let mut writer = BufWriter::new(file);
+            // Written as inactive until the length is known
+            writer.write_all(&[0])?;
+            writer.write_all(uuid.as_bytes())?;
+            writer.write_all(PENDING_RECORD_SIZE.to_le_bytes().as_slice())?;
+            let size = io::copy(reader, &mut writer)?;
+            writer.flush()?;
+            Ok(size)
+        };
+        let size = match copy(&mut file) {
+            Ok(size) => size,
+            Err(err) => {
+                file.set_len(header_start)?;
+                return Err(err);
+            },
+        };
+        // The length goes first, so the record is never active with the placeholder
+        file.seek(SeekFrom::Start(header_start + RECORD_BIT_FLAGS_SIZE_BYTES + RECORD_ID_SIZE_BYTES))?;
+        file.write_all(size.to_le_bytes().as_slice())?;
+        file.seek(SeekFrom::Start(header_start))?;
+        file.write_all(&[RECORD_ACTIVE_FLAG | encoding.flags()])?;
+
         self.records.insert(
             uuid,
             RecordInformation {
                 is_active: true,
+                is_alias: false,
+                encoding,
+                header_start,
                 start: self.next_start,
-                size: data.len() as u64
+                size,
             }
         );
+        self.next_start += size + RECORD_HEADER_SIZE_BYTES;
 
-        self.next_start += data.len() as u64 + RECORD_HEADER_SIZE_BYTES;
+        Ok(size)
+    }
 
-        Ok(uuid)
+    /// Appends a record that shares the data of an existing active record, instead of
+    /// storing another copy of it.
+    ///
+    /// The data is kept by compactions as long as any record pointing to it is active,
+    /// even if the original record is deleted or replaced.
+    pub fn push_alias(&mut self, uuid: uuid::Uuid, target_id: &uuid::Uuid) -> io::Result<Option<uuid::Uuid>> {
+        let Some(target) = self.records.get(target_id).filter(|record| record.is_active) else {
+            return Ok(None);
+        };
+        let record_info = RecordInformation {
+            is_active: true,
+            is_alias: true,
+            // Aliases share the encoded data, like the manifest of chunked records
+            encoding: target.encoding,
+            header_start: self.next_start - RECORD_HEADER_SIZE_BYTES,
+            start: target.start,
+            size: target.size,
+        };
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&self.file_path)?;
+
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&[record_info.flags()])?;
+        writer.write_all(uuid.as_bytes())?;
+        writer.write_all(ALIAS_PAYLOAD_SIZE_BYTES.to_le_bytes().as_slice())?;
+        writer.write_all(record_info.start.to_le_bytes().as_slice())?;
+        writer.write_all(record_info.size.to_le_bytes().as_slice())?;
+
+        self.records.insert(uuid, record_info);
+
+        self.next_start += ALIAS_PAYLOAD_SIZE_BYTES + RECORD_HEADER_SIZE_BYTES;
+
+        Ok(Some(uuid))
     }
 
     pub fn get_record_information(&self, record_id: &uuid::Uuid) -> Option<&RecordInformation> {
         self.records.get(record_id)
     }
 
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// Reads up to `len` bytes of the partition file starting at `offset`.
+    ///
+    /// Less bytes are returned if the end of the file is reached.
+    pub fn read_at(&self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(&self.file_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(len as usize);
+        file.take(len).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Marks a record as inactive, so it will be deleted in the next compaction.
+    ///
+    /// Returns false if the record doesn't exist or was already inactive.
+    pub fn delete_record(&mut self, record_id: &uuid::Uuid) -> io::Result<bool> {
+        let record_info = match self.records.get_mut(record_id) {
+            Some(record_info) if record_info.is_active => record_info,
+            _ => return Ok(false),
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.file_path)?;
+        // The flags are the first byte of the record header
+        let flags_position = record_info.header_start;
+        let mut flags: [u8; 1] = [0];
+        file.seek(SeekFrom::Start(flags_position))?;
+        file.read_exact(&mut flags)?;
+
+        file.seek(SeekFrom::Start(flags_position))?;
+        file.write_all(&[flags[0] & !RECORD_ACTIVE_FLAG])?;
+
+        record_info.is_active = false;
+        Ok(true)
+    }
+
+    /// Checks whether an active record with the given id exists in the partition.
+    pub fn contains_record(&self, record_id: &uuid::Uuid) -> bool {
+        self.records.get(record_id).is_some_and(|record| record.is_active)
+    }
+
     pub fn fetch_record(&self, record_id: &uuid::Uuid) -> io::Result<Option<io::Take<BufReader<File>>>> {
-        match self.records.get(record_id) {
+        match self.records.get(record_id).filter(|record| record.is_active) {
             Some(record_info) => {
                 let file = File::open(&self.file_path)?;
                 let mut reader = BufReader::new(file);
@@ -188,9 +672,210 @@ impl Partition {
         }
     }
 
+    /// The id of the record that wrote the data of a record. Aliases share the data of
+    /// another record, whose header is right before the data, even if it was deleted.
+    pub fn data_owner(&self, record_id: &uuid::Uuid, record_info: &RecordInformation) -> io::Result<uuid::Uuid> {
+        if !record_info.is_alias {
+            return Ok(*record_id);
+        }
+        let id_start = record_info.start
+            .checked_sub(RECORD_HEADER_SIZE_BYTES - RECORD_BIT_FLAGS_SIZE_BYTES)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Alias of data without a header"))?;
+        let id = self.read_at(id_start, RECORD_ID_SIZE_BYTES)?;
+        uuid::Uuid::from_slice(&id).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Truncated record header"))
+    }
+
+    /// Rewrites the partition file without its inactive records, returning the number of
+    /// bytes reclaimed.
+    ///
+    /// Data shared by several records is written once, under the first active record
+    /// that points to it, and the rest of them become aliases of that record. Data that
+    /// is only referenced by inactive records is dropped.
+    pub fn compact(&mut self) -> io::Result<u64> {
+        self.compact_with(|_, _, _| Ok(None))
+    }
+
+    /// Like `compact`, re-encoding the data of some records while they are moved.
+    ///
+    /// `reencode` is called once for every piece of data that is kept, and returns the new
+    /// encoding of the data along with a reader of it, or `None` to keep it as it is.
+    pub fn compact_with<F>(&mut self, mut reencode: F) -> io::Result<u64>
+    where
+        F: FnMut(&Partition, &uuid::Uuid, &RecordInformation) -> io::Result<Option<(RecordEncoding, Box<dyn Read>)>>
+    {
+        let old_len = std::fs::metadata(&self.file_path)?.len();
+        // Dot files are not loaded as partitions, in case the server stops while compacting
+        let file_name = self.file_path.file_name().expect("partitions to be files").to_string_lossy();
+        let compacting_path = self.file_path.with_file_name(format!(".{}.compacting", file_name.trim_start_matches('.')));
+
+        let mut active = self.records
+            .iter()
+            .filter(|(_, record)| record.is_active)
+            .collect::<Vec<_>>();
+        // Keep the records in the order they were written
+        active.sort_by_key(|(_, record)| record.header_start);
+
+        let last_compaction = VennTimestamp::now();
+        let mut source = BufReader::new(File::open(&self.file_path)?);
+        let mut writer = BufWriter::new(File::create(&compacting_path)?);
+        writer.write_all(self.created_at.0.to_le_bytes().as_slice())?;
+        writer.write_all(last_compaction.0.to_le_bytes().as_slice())?;
+
+        let mut records = HashMap::with_capacity(active.len());
+        // Where the data that was at a given offset has been moved, with its new size and
+        // encoding
+        let mut moved_data: HashMap<u64, (u64, u64, RecordEncoding)> = HashMap::new();
+        let mut position = PARTITION_HEADER_BYTES_OFFSET;
+        for (uuid, record) in active {
+            let header_start = position;
+            let is_alias = match moved_data.get(&record.start) {
+                Some(&(start, size, encoding)) => {
+                    writer.write_all(&[RECORD_ACTIVE_FLAG | RECORD_ALIAS_FLAG | encoding.flags()])?;
+                    writer.write_all(uuid.as_bytes())?;
+                    writer.write_all(ALIAS_PAYLOAD_SIZE_BYTES.to_le_bytes().as_slice())?;
+                    writer.write_all(start.to_le_bytes().as_slice())?;
+                    writer.write_all(size.to_le_bytes().as_slice())?;
+                    true
+                },
+                None => {
+                    let (encoding, size) = match reencode(self, uuid, record)? {
+                        Some((encoding, mut reader)) => {
+                            // The length is unknown until the data is written, like in
+                            // `push_record_from_reader`
+                            writer.write_all(&[RECORD_ACTIVE_FLAG | encoding.flags()])?;
+                            writer.write_all(uuid.as_bytes())?;
+                            writer.write_all(0u64.to_le_bytes().as_slice())?;
+                            let size = io::copy(&mut reader, &mut writer)?;
+                            let data_start = header_start + RECORD_HEADER_SIZE_BYTES;
+                            writer.seek(SeekFrom::Start(data_start - RECORD_DATA_LENGTH_SIZE_BYTES))?;
+                            writer.write_all(size.to_le_bytes().as_slice())?;
+                            writer.seek(SeekFrom::Start(data_start + size))?;
+                            (encoding, size)
+                        },
+                        None => {
+                            writer.write_all(&[RECORD_ACTIVE_FLAG | record.encoding.flags()])?;
+                            writer.write_all(uuid.as_bytes())?;
+                            writer.write_all(record.size.to_le_bytes().as_slice())?;
+                            source.seek(SeekFrom::Start(record.start))?;
+                            let copied = io::copy(&mut source.by_ref().take(record.size), &mut writer)?;
+                            if copied != record.size {
+                                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated record data"));
+                            }
+                            (record.encoding, record.size)
+                        },
+                    };
+                    moved_data.insert(record.start, (header_start + RECORD_HEADER_SIZE_BYTES, size, encoding));
+                    false
+                },
+            };
+            let (start, size, encoding) = moved_data[&record.start];
+            let new_record = RecordInformation {
+                is_active: true,
+                is_alias,
+                encoding,
+                header_start,
+                start,
+                size
+            };
+            position += new_record.stored_size();
+            records.insert(*uuid, new_record);
+        }
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&compacting_path, &self.file_path)?;
+
+        self.records = records;
+        self.last_compaction = last_compaction;
+        self.next_start = position + RECORD_HEADER_SIZE_BYTES;
+        Ok(old_len.saturating_sub(position))
+    }
+
     pub fn iter_active_records(&self) -> impl Iterator<Item=(&uuid::Uuid, &RecordInformation)> {
         self.records
             .iter()
             .filter(|(_, record)| record.is_active)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::vennbase::testing::temp_path;
+
+    fn read_all(partition: &Partition, id: &uuid::Uuid) -> Vec<u8> {
+        let mut data = Vec::new();
+        partition.fetch_record(id).unwrap().unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn aliases_survive_compaction() -> io::Result<()> {
+        let path = temp_path();
+        let mut partition = Partition::create(path.clone())?;
+        let deleted = partition.push_record(b"deleted")?;
+        let original = partition.push_record(b"shared data")?;
+        let alias = partition.push_alias(uuid::Uuid::new_v4(), &original)?.unwrap();
+        partition.delete_record(&deleted)?;
+        partition.delete_record(&original)?;
+        assert_eq!(read_all(&partition, &alias), b"shared data");
+
+        assert!(partition.compact()? > 0);
+        let partition = Partition::from_file(path.clone())?;
+        assert_eq!(partition.records_len(), 1);
+        assert!(!partition.get_record_information(&alias).unwrap().is_alias());
+        assert_eq!(read_all(&partition, &alias), b"shared data");
+
+        std::fs::remove_file(path)
+    }
+
+    #[test]
+    fn streamed_records_can_be_read_as_chunks() -> io::Result<()> {
+        let path = temp_path();
+        let mut partition = Partition::create(path.clone())?;
+        let first = uuid::Uuid::new_v4();
+        let second = uuid::Uuid::new_v4();
+        assert_eq!(partition.push_record_from_reader(first, &mut &b"hello "[..], RecordEncoding::default())?, 6);
+        assert_eq!(partition.push_record_from_reader(second, &mut &b"world"[..], RecordEncoding::default())?, 5);
+
+        let partition = Partition::from_file(path.clone())?;
+        assert_eq!(read_all(&partition, &second), b"world");
+        let chunks = [first, second].map(|id| {
+            let record_info = partition.get_record_information(&id).unwrap();
+            Chunk { id, start: record_info.start(), size: record_info.size(), encrypted: false }
+        });
+        let reader = ChunksReader::new(&path, chunks.to_vec(), None)?;
+        assert_eq!(reader.size(), 11);
+        let mut data = String::new();
+        reader.into_range(4, 7)?.read_to_string(&mut data)?;
+        assert_eq!(data, "o wo");
+
+        std::fs::remove_file(path)
+    }
+
+    #[test]
+    fn unfinished_records_are_truncated() -> io::Result<()> {
+        let path = temp_path();
+        let mut partition = Partition::create(path.clone())?;
+        let saved = partition.push_record(b"saved")?;
+        let complete_len = std::fs::metadata(&path)?.len();
+
+        // Like a record being streamed when the process stopped
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[0])?;
+        file.write_all(uuid::Uuid::new_v4().as_bytes())?;
+        file.write_all(PENDING_RECORD_SIZE.to_le_bytes().as_slice())?;
+        file.write_all(b"half of the da")?;
+        drop(file);
+
+        let mut partition = Partition::from_file(path.clone())?;
+        assert_eq!(std::fs::metadata(&path)?.len(), complete_len);
+        assert_eq!(partition.records_len(), 1);
+        let pushed = partition.push_record(b"pushed")?;
+
+        let partition = Partition::from_file(path.clone())?;
+        assert_eq!(read_all(&partition, &saved), b"saved");
+        assert_eq!(read_all(&partition, &pushed), b"pushed");
+
+        std::fs::remove_file(path)
+    }
+}
@@ -0,0 +1,90 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufWriter, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::db::partition::RecordEncoding;
+use crate::features::compression::CompressingReader;
+use crate::features::dedup::HashingReader;
+use crate::features::encryption::EncryptionKeys;
+
+/// Encodes the data of a new record as it must be stored, see `Vennbase::record_encoder`.
+///
+/// Encoders don't borrow the database, so the data can be received from a client
+/// without holding the database lock.
+pub struct RecordEncoder {
+    pub id: uuid::Uuid,
+    pub encoding: RecordEncoding,
+    keys: Option<Arc<EncryptionKeys>>,
+    // Maximum number of bytes read from the data
+    limit: u64,
+    spool_dir: PathBuf,
+}
+
+impl RecordEncoder {
+    pub fn new(
+        id: uuid::Uuid,
+        encoding: RecordEncoding,
+        keys: Option<Arc<EncryptionKeys>>,
+        spool_dir: PathBuf
+    ) -> Self {
+        RecordEncoder { id, encoding, keys, limit: u64::MAX, spool_dir }
+    }
+
+    /// Stops reading the data after `limit` bytes.
+    pub fn with_limit(self, limit: u64) -> Self {
+        RecordEncoder { limit, ..self }
+    }
+
+    /// Encodes the data while it's read into a spool file, so it's stored once it's
+    /// complete. The plaintext data is never written to the spool.
+    pub fn spool(self, reader: &mut impl Read) -> io::Result<SpooledRecord> {
+        fs::create_dir_all(&self.spool_dir)?;
+        let path = self.spool_dir.join(self.id.to_string());
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        // Removes the file if anything fails from now on
+        let mut spooled = SpooledRecord { id: self.id, encoding: self.encoding, hash: String::new(), file, path };
+
+        let mut reader = HashingReader::new(reader.take(self.limit));
+        let mut encoded: Box<dyn Read> = Box::new(&mut reader);
+        if self.encoding.compressed {
+            encoded = Box::new(CompressingReader::new(encoded));
+        }
+        if let Some(keys) = self.keys.filter(|_| self.encoding.encrypted) {
+            encoded = Box::new(keys.encrypting_reader(&self.id, encoded));
+        }
+        let mut writer = BufWriter::new(&mut spooled.file);
+        io::copy(&mut encoded, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        drop(encoded);
+
+        spooled.hash = reader.finish();
+        spooled.file.seek(SeekFrom::Start(0))?;
+        Ok(spooled)
+    }
+}
+
+/// The encoded data of a new record, waiting in a spool file until it's stored. The
+/// file is removed when the record is dropped.
+pub struct SpooledRecord {
+    pub id: uuid::Uuid,
+    pub encoding: RecordEncoding,
+    /// Content hash of the data before it was encoded, see `features::dedup`
+    pub hash: String,
+    file: File,
+    path: PathBuf,
+}
+
+impl SpooledRecord {
+    /// The encoded data.
+    pub fn reader(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+impl Drop for SpooledRecord {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
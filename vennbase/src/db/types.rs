@@ -62,6 +62,19 @@ impl MimeType {
     pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
+
+    /// Checks whether the mimetype matches a `mime:` filter.
+    ///
+    /// Filters can be a full mimetype (`image/png`), a wildcard subtype (`image/*`) or a
+    /// single `*`, which matches every mimetype.
+    pub fn matches(&self, pattern: &str) -> bool {
+        match pattern.split_once('/') {
+            Some((kind, "*")) => self.0
+                .split_once('/')
+                .is_some_and(|(k, _)| k.eq_ignore_ascii_case(kind)),
+            _ => pattern == "*" || pattern.eq_ignore_ascii_case(&self.0),
+        }
+    }
 }
 
 // We implemented the Debug trait ourselves so that it doesn't print an unnecessary line break
@@ -77,6 +90,21 @@ impl std::fmt::Display for MimeType {
     }
 }
 
+// Mimetypes are stored as strings, and checked like the ones received from clients
+impl serde::Serialize for MimeType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for MimeType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mimetype = String::deserialize(deserializer)?;
+        MimeType::from(mimetype.as_str())
+            .map_err(|_| serde::de::Error::custom(format!("invalid mimetype '{mimetype}'")))
+    }
+}
+
 impl From<String> for MimeType {
     fn from(s: String) -> Self {
         MimeType(s)
@@ -88,3 +116,72 @@ impl From<&str> for MimeType {
         MimeType(value.to_string())
     }
 }
+
+/// A `range=` of bytes requested by `get`, with the same forms as HTTP ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `<start>-<end>`, both included
+    Bounded(u64, u64),
+    /// `<start>-`, up to the end of the record
+    From(u64),
+    /// `-<n>`, the last `n` bytes of the record
+    Suffix(u64),
+}
+
+#[derive(Debug)]
+pub struct InvalidByteRange;
+
+impl ByteRange {
+    pub fn from_range_str(range: &str) -> Result<Self, InvalidByteRange> {
+        let (start, end) = range.split_once('-').ok_or(InvalidByteRange)?;
+        let parse = |n: &str| n.parse::<u64>().map_err(|_| InvalidByteRange);
+        match (start, end) {
+            ("", "") => Err(InvalidByteRange),
+            ("", n) => Ok(ByteRange::Suffix(parse(n)?)),
+            (start, "") => Ok(ByteRange::From(parse(start)?)),
+            (start, end) => Ok(ByteRange::Bounded(parse(start)?, parse(end)?)),
+        }
+    }
+
+    /// The first and last bytes (both included) of the range in data of the given length.
+    ///
+    /// Ends past the data are clamped to its last byte. Returns `None` if the range doesn't
+    /// contain any byte of the data.
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        let (start, end) = match *self {
+            ByteRange::Bounded(start, end) => (start, end.min(len.checked_sub(1)?)),
+            ByteRange::From(start) => (start, len.checked_sub(1)?),
+            ByteRange::Suffix(0) => return None,
+            ByteRange::Suffix(n) => (len.saturating_sub(n), len.checked_sub(1)?),
+        };
+        (start <= end).then_some((start, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_resolved_like_http() {
+        let range = |s: &str| ByteRange::from_range_str(s).unwrap();
+        assert_eq!(range("0-99").resolve(1000), Some((0, 99)));
+        assert_eq!(range("900-").resolve(1000), Some((900, 999)));
+        assert_eq!(range("-100").resolve(1000), Some((900, 999)));
+        assert_eq!(range("-5000").resolve(1000), Some((0, 999)));
+        assert_eq!(range("500-5000").resolve(1000), Some((500, 999)));
+
+        assert_eq!(range("1000-").resolve(1000), None);
+        assert_eq!(range("20-10").resolve(1000), None);
+        assert_eq!(range("0-0").resolve(0), None);
+        assert!(ByteRange::from_range_str("-").is_err());
+        assert!(ByteRange::from_range_str("a-10").is_err());
+    }
+
+    #[test]
+    fn stored_mimetypes_are_validated() {
+        let mimetype: MimeType = serde_json::from_str("\"image/png\"").unwrap();
+        assert_eq!(serde_json::to_string(&mimetype).unwrap(), "\"image/png\"");
+        assert!(serde_json::from_str::<MimeType>("\"not a mimetype\"").is_err());
+    }
+}
@@ -1,16 +1,41 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, hash_map};
 use core::panic;
-use std::fs::{self, File};
-use std::io::{self, prelude::*, BufWriter, BufReader};
+use std::fs;
+use std::io::{self, prelude::*};
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use crate::db::types::{VennTimestamp, MimeType};
-use crate::db::partition::{Partition, StoredRecord};
+use crate::db::types::MimeType;
+use crate::db::partition::{Chunk, ChunksReader, Partition, RecordEncoding, RecordInformation, StoredRecord};
+use crate::db::spool::{RecordEncoder, SpooledRecord};
+use crate::config::VennbaseConfig;
+use crate::features::cache::{CacheStats, LRUCache, LRUCacheBuilder};
+use crate::features::shared_buffers::SharedBuffers;
 use crate::features::fast_querying::InvertedIndexMap;
-use crate::features::resize::{Dimensions, is_resizable_format, resize_image};
-use crate::query::parse_query;
+use crate::features::views::{ViewsMap, ViewError};
+use crate::features::exif::{jpeg_orientation, strip_jpeg_metadata, strip_png_metadata};
+use crate::features::metadata::{ImageMetadata, MetadataMap};
+use crate::features::similarity::hamming_distance;
+use crate::features::sniffing::{
+    MimeValidation, SniffError, SNIFFED_HEAD_LENGTH, check_signature, detect_mime_type, validate_mime_type,
+};
+use crate::features::dedup::{ContentHashMap, DedupMode, HashingReader, ReplaceError, content_hash};
+use crate::features::uploads::{UploadError, UploadsMap};
+use crate::features::compression::{
+    DecompressedRecord, TRAILER_SIZE_BYTES, compress, should_compress,
+};
+use crate::features::encryption::{EncryptionKeys, SideFile, plaintext_size};
+use crate::features::resize::{
+    Dimensions, EncodeOptions, Gravity, ResizeFilter, ResizeMode, ResizeOptions,
+    decode_record_image, is_resizable_format, resize_decoded_image, resize_image,
+};
+use crate::query::{
+    parse_query, parse_query_checked, parse_comparison, evaluate, normalize,
+    IndexLookup, QueryError, QueryErrorKind, QueryPlan, VariablesPermutations,
+    PropositionType::{Fixed, Fickle},
+};
 
-use image::ImageFormat;
+use image::{DynamicImage, ImageFormat};
 use logic_parser::parsing::ASTNode;
 
 /// A venbase database instance.
@@ -21,223 +46,1581 @@ pub struct Vennbase {
     path: PathBuf,
     partitions: HashMap<MimeType, Partition>,
     tags: InvertedIndexMap,
+    views: ViewsMap,
+    query_cache: LRUCache<String, Arc<CachedQuery>>,
+    shared_buffers: SharedBuffers,
+    resize_cache: LRUCache<ResizeKey, Arc<Vec<u8>>>,
+    // Derived images generated at save time, see `VennbaseConfig::variants`
+    variants: Partition,
+    variant_profiles: HashMap<String, ResizeOptions>,
+    // Used when a resize request doesn't specify an algorithm
+    resize_filter: ResizeFilter,
+    // Used when a resize request doesn't specify encoder settings
+    encoding: EncodeOptions,
+    // Properties of the images, read when they are saved
+    metadata: MetadataMap,
+    mime_validation: MimeValidation,
+    // Hashes of the data of every record, used to find duplicates
+    hashes: ContentHashMap,
+    dedup: DedupMode,
+    // Pieces of the records saved with multi-part uploads, see `Partition::push_chunked_record`
+    chunks: Partition,
+    uploads: UploadsMap,
+    upload_chunk_size: u64,
+    // Mimetype patterns of the records stored compressed
+    compression: Vec<String>,
+    // Keys of the records and side files stored encrypted, see `VennbaseConfig::encryption`
+    encryption: Option<Arc<EncryptionKeys>>,
 }
 
-#[derive(Debug)]
-pub struct VennbaseError(String);
+// Resized images are identified by the original record, how it was resized and the
+// output format
+type ResizeKey = (uuid::Uuid, ResizeOptions, ImageFormat);
+
+/// The results of a query, along with what it depends on
+struct CachedQuery {
+    records: Arc<Vec<(MimeType, uuid::Uuid)>>,
+    // Partitions that were scanned to evaluate the query
+    scanned: Vec<MimeType>,
+    // Tags referenced by the query
+    tags: Vec<String>,
+}
+
+fn new_resize_cache(config: &VennbaseConfig) -> LRUCache<ResizeKey, Arc<Vec<u8>>> {
+    LRUCacheBuilder::<Arc<Vec<u8>>>::new()
+        .with_max_size(config.resize_cache_size)
+        .with_calc_size(|data| data.len())
+        .build()
+}
+
+fn new_query_cache(config: &VennbaseConfig) -> LRUCache<String, Arc<CachedQuery>> {
+    LRUCacheBuilder::<Arc<CachedQuery>>::new()
+        .with_max_size(config.query_cache_records)
+        // Empty results still take an entry
+        .with_calc_size(|cached| cached.records.len() + 1)
+        .build()
+}
+
+fn parse_variant_profiles(config: &VennbaseConfig) -> io::Result<HashMap<String, ResizeOptions>> {
+    config.variants
+        .iter()
+        .map(|(name, dims)| {
+            if !is_valid_variant_name(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid variant name: {name:?}")
+                ));
+            }
+            let dims = Dimensions::from_dim_str(dims).map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid dimensions for variant {name:?}: {dims:?}")
+            ))?;
+            Ok((name.to_owned(), ResizeOptions {
+                dims,
+                filter: config.resize_filter,
+                mode: ResizeMode::default(),
+                gravity: Gravity::default(),
+                output: None,
+                encoding: config.encoding(),
+                first_frame: false,
+            }))
+        })
+        .collect()
+}
+
+pub fn is_valid_variant_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Reads the metadata of an image record, see `ImageMetadata::from_image`.
+fn read_image_metadata(
+    record_id: &uuid::Uuid,
+    mimetype: &MimeType,
+    data: &[u8],
+    image: Option<&DynamicImage>
+) -> Option<ImageMetadata> {
+    // MIMEtype should be valid at this point
+    let format = ImageFormat::from_mime_type(mimetype.as_str()).unwrap();
+    let metadata = ImageMetadata::from_image(data, format, image);
+    if metadata.as_ref().is_none_or(|metadata| metadata.dhash.is_none()) {
+        println!("Couldn't decode the image {record_id}");
+    }
+    metadata
+}
+
+/// Variants are stored with an id derived from the original record and the profile
+/// name, so they can be found without keeping an index.
+fn variant_id(record_id: &uuid::Uuid, profile: &str) -> uuid::Uuid {
+    uuid::Uuid::new_v5(record_id, profile.as_bytes())
+}
+
+/// Length of the data of an upload chunk, which may be stored encrypted.
+fn chunk_size(chunk: &RecordInformation) -> u64 {
+    match chunk.encoding().encrypted {
+        true => plaintext_size(chunk.size()).unwrap_or(0),
+        false => chunk.size(),
+    }
+}
+
+/// How compactions re-encode the data of a record, see `Partition::compact_with`.
+///
+/// Records encrypted with a previous key are encrypted again with the current one, and
+/// plain records are encrypted if `must_encrypt`. Data that moves from a deleted record
+/// to one of its aliases is encrypted again for the alias. Manifests of chunked records
+/// are left as they are, their chunks are encrypted instead.
+fn reencrypt_record(
+    keys: Option<&EncryptionKeys>,
+    partition: &Partition,
+    id: &uuid::Uuid,
+    record_info: &RecordInformation,
+    must_encrypt: bool
+) -> io::Result<Option<(RecordEncoding, Box<dyn Read>)>> {
+    let encoding = record_info.encoding();
+    let Some(keys) = keys.filter(|_| !encoding.chunked && (encoding.encrypted || must_encrypt)) else {
+        return Ok(None);
+    };
+    let Some(reader) = partition.fetch_record(id)? else {
+        return Ok(None);
+    };
+    let mut record = StoredRecord::InDiskRecord(reader);
+    if encoding.encrypted {
+        let owner = partition.data_owner(id, record_info)?;
+        let decrypted = keys.decrypt_record(&owner, record)?;
+        if keys.is_current_key(&decrypted) && owner == *id {
+            return Ok(None);
+        }
+        record = StoredRecord::Decrypted(decrypted);
+    }
+    Ok(Some((RecordEncoding { encrypted: true, ..encoding }, Box::new(keys.encrypting_reader(id, record)))))
+}
+
+/// Iterator over the records matching a query, see `Vennbase::iter_query_records`.
+pub struct QueryMatches<'a> {
+    db: &'a Vennbase,
+    tree: ASTNode,
+    partitions: hash_map::Iter<'a, MimeType, Partition>,
+    current: Option<(&'a MimeType, ActiveRecords<'a>)>,
+}
+
+type ActiveRecords<'a> = Box<dyn Iterator<Item=(&'a uuid::Uuid, &'a RecordInformation)> + 'a>;
+
+impl<'a> Iterator for QueryMatches<'a> {
+    type Item = Result<(&'a MimeType, &'a uuid::Uuid), QueryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((mimetype, records)) = self.current.as_mut() {
+                for (uuid, _) in records.by_ref() {
+                    match self.db.evaluate_for_record(&self.tree, mimetype, uuid) {
+                        Ok(true) => return Some(Ok((*mimetype, uuid))),
+                        Ok(false) => continue,
+                        Err(_) => return Some(Err(QueryError {
+                            kind: QueryErrorKind::Evaluation,
+                            offset: 0,
+                            message: format!("failed to evaluate record {uuid}"),
+                        })),
+                    }
+                }
+            }
+            // The current partition is exhausted, move to the next one that can match.
+            // Since the query may contain some MimeType criteria, we have the advantage of
+            // determining which partitions to skip
+            let (mimetype, partition) = self.partitions
+                .by_ref()
+                .find(|(mimetype, _)| self.db.should_scan_partition(&self.tree, mimetype))?;
+            self.current = Some((mimetype, Box::new(partition.iter_active_records())));
+        }
+    }
+}
+
+// Above this number of non-`mime:` propositions, partition pruning stops being worth it
+const MAX_FICKLE_PROPOSITIONS: usize = 12;
 
 impl Vennbase {
     /// Parses an existing vennbase database directory
-    pub fn from_dir(path: &str) -> io::Result<Vennbase> {
+    pub fn from_dir(path: &str, config: &VennbaseConfig) -> io::Result<Vennbase> {
         match fs::create_dir(path) {
             Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
-                let tree = Vennbase::parse_dir_tree(path).expect("Malformed database directory");
+                let tree = Vennbase::parse_dir_tree(path, config).expect("Malformed database directory");
                 Ok(tree)
             }
             Err(e) => panic!("Couldn't create database directory: {:#?}", e),
             Ok(_) => {
+                let encryption = EncryptionKeys::load(&config.encryption)?;
+                let side_file = |name: &str| SideFile::new(PathBuf::from(path).join(name), encryption.clone());
                 // create a .map file
-                let map_path = PathBuf::from(path).join(".map");
-                let file = File::create(map_path.clone())?;
-                let mut writer = BufWriter::new(file);
-                let tags_map = InvertedIndexMap { map: HashMap::new(), path: map_path.clone() };
-                let serialized_map = serde_json::to_string(&tags_map).unwrap();
-                writer.write_all(serialized_map.as_bytes())?;
+                let tags_map = InvertedIndexMap { map: HashMap::new(), file: side_file(".map") };
+                tags_map.file.write_json(&tags_map)?;
 
                 Ok(Vennbase {
                     path: path.into(),
                     partitions: HashMap::new(),
-                    tags: tags_map
+                    tags: tags_map,
+                    views: ViewsMap::from_file(side_file(".views"))?,
+                    query_cache: new_query_cache(config),
+                    shared_buffers: SharedBuffers::new(config.shared_buffers, config.max_buffered_record_size),
+                    resize_cache: new_resize_cache(config),
+                    variants: Partition::open_or_create(PathBuf::from(path).join(".variants"))?,
+                    variant_profiles: parse_variant_profiles(config)?,
+                    resize_filter: config.resize_filter,
+                    encoding: config.encoding(),
+                    metadata: MetadataMap::from_file(side_file(".meta"))?,
+                    mime_validation: config.mime_validation,
+                    hashes: ContentHashMap::from_file(side_file(".hashes"))?,
+                    dedup: config.dedup,
+                    chunks: Partition::open_or_create(PathBuf::from(path).join(".chunks"))?,
+                    uploads: UploadsMap::from_file(side_file(".uploads"))?,
+                    upload_chunk_size: config.upload_chunk_size,
+                    compression: config.compression.clone(),
+                    encryption,
                 })
             },
         }
     }
 
+    /// Checks that the data of a new record looks like its declared mimetype, according
+    /// to `VennbaseConfig::mime_validation`. Returns the mimetype it should be saved with,
+    /// and the decoded image to save it with `save_decoded_record`.
+    pub fn validate_record(&self, mimetype: &MimeType, data: &[u8]) -> Result<(MimeType, Option<DynamicImage>), SniffError> {
+        validate_mime_type(mimetype, data, self.mime_validation)
+    }
+
+    /// Like `validate_record`, for records that are saved without holding them in memory.
+    /// Only the first bytes of the data are checked, see `SNIFFED_HEAD_LENGTH`.
+    pub fn validate_record_head(&self, mimetype: &MimeType, head: &[u8]) -> Result<MimeType, SniffError> {
+        check_signature(mimetype, head, self.mime_validation)
+    }
+
+    /// Like `validate_record`, for the new data of an existing record. Returns the decoded
+    /// image to replace the record with `replace_decoded_record`.
+    ///
+    /// Replaced records can't move to another partition, so mismatches are always rejected.
+    /// Records returned by several saves in `existing` dedup mode can't be replaced either,
+    /// since the other saves would get the new data too.
+    pub fn validate_replacement(&self, id: &uuid::Uuid, data: &[u8]) -> Result<Option<DynamicImage>, ReplaceError> {
+        let references = self.hashes.references(id);
+        if references > 0 {
+            return Err(ReplaceError::Shared { id: *id, saves: references + 1 });
+        }
+        let validation = match self.mime_validation {
+            MimeValidation::Off => MimeValidation::Off,
+            _ => MimeValidation::Reject,
+        };
+        match self.find_record_partition(id) {
+            Some(mimetype) => Ok(validate_mime_type(mimetype, data, validation)?.1),
+            None => Ok(None),
+        }
+    }
+
     /// Saves a new record the database and returns its UUID.
     ///
-    /// If the partition for the given mimetype doesn't exist, it will be created.
+    /// If the partition for the given mimetype doesn't exist, it will be created. If the
+    /// partition already holds the same data, it's deduplicated according to
+    /// `VennbaseConfig::dedup`.
     pub fn save_record(
         &mut self,
         mimetype: &MimeType, data: &[u8],
         tags: Vec<String>
     ) -> io::Result<uuid::Uuid> {
-        let partition = self.get_mut_or_create_partition(mimetype)?;
-        partition.push_record(data).map(|uuid| {
-            for t in tags {
-                self.tags.add_tag(t.as_str(), uuid);
+        let image = decode_record_image(mimetype, data).and_then(Result::ok);
+        self.save_decoded_record(mimetype, data, image.as_ref(), tags)
+    }
+
+    /// Like `save_record`, for an image that was already decoded by `validate_record`. The
+    /// image is used to read its metadata and generate its variants.
+    pub fn save_decoded_record(
+        &mut self,
+        mimetype: &MimeType, data: &[u8],
+        image: Option<&DynamicImage>,
+        tags: Vec<String>
+    ) -> io::Result<uuid::Uuid> {
+        let hash = content_hash(data);
+        match (self.dedup, self.find_duplicate(mimetype, &hash)) {
+            (DedupMode::Existing, Some(existing)) => return self.reference_record(&existing, tags),
+            (DedupMode::Alias, Some(existing)) => return self.save_alias(mimetype, &existing, hash, tags),
+            _ => (),
+        }
+
+        let uuid = uuid::Uuid::new_v4();
+        match self.encode_record(&uuid, mimetype, data) {
+            Some((encoding, encoded)) => self
+                .partition_for_new_record(mimetype)?
+                .push_record_with_encoding(uuid, &encoded, encoding)?,
+            None => self.partition_for_new_record(mimetype)?.push_record_with_id(uuid, data)?,
+        };
+        let partition = &self.partitions[mimetype];
+        let record_info = partition.get_record_information(&uuid).expect("to exist since it was just pushed");
+        // The last page of the partition may be cached without the new record
+        self.shared_buffers.invalidate_record(partition, record_info);
+        for t in tags {
+            self.tags.add_tag(t.as_str(), uuid);
+        }
+        self.store_metadata(&uuid, mimetype, data, image)?;
+        self.generate_variants(&uuid, mimetype, data, image)?;
+        self.hashes.insert(uuid, hash)?;
+        Ok(uuid)
+    }
+
+    /// Prepares the encoding of a new record, so its data can be received without holding
+    /// the database lock. See `save_spooled_record`.
+    pub fn record_encoder(&self, mimetype: &MimeType) -> RecordEncoder {
+        let encoding = RecordEncoding {
+            compressed: should_compress(&self.compression, mimetype),
+            encrypted: self.should_encrypt(mimetype),
+            ..Default::default()
+        };
+        RecordEncoder::new(uuid::Uuid::new_v4(), encoding, self.encryption.clone(), self.spool_dir())
+    }
+
+    /// Saves a new record from its spooled data, without holding it in memory.
+    ///
+    /// Records are compressed and encrypted as they are spooled, even if they don't get
+    /// any smaller. Images are not decoded, so they are saved without metadata nor
+    /// variants. Since the data is hashed as it's spooled, duplicates are written before
+    /// being found, and they take space in the partition until the next compaction.
+    pub fn save_spooled_record(
+        &mut self,
+        mimetype: &MimeType, mut spooled: SpooledRecord,
+        tags: Vec<String>
+    ) -> io::Result<uuid::Uuid> {
+        let uuid = spooled.id;
+        let encoding = spooled.encoding;
+        self.partition_for_new_record(mimetype)?.push_record_from_reader(uuid, spooled.reader(), encoding)?;
+        let hash = std::mem::take(&mut spooled.hash);
+
+        let duplicate = self.find_duplicate(mimetype, &hash).filter(|_| self.dedup != DedupMode::Off);
+        let partition = self.partitions.get_mut(mimetype).expect("to exist since the record was just pushed");
+        if duplicate.is_some() {
+            partition.delete_record(&uuid)?;
+        }
+        let record_info = partition.get_record_information(&uuid).expect("to exist since it was just pushed");
+        self.shared_buffers.invalidate_record(partition, record_info);
+        match (self.dedup, duplicate) {
+            (DedupMode::Existing, Some(existing)) => return self.reference_record(&existing, tags),
+            (DedupMode::Alias, Some(existing)) => return self.save_alias(mimetype, &existing, hash, tags),
+            _ => (),
+        }
+        for t in tags {
+            self.tags.add_tag(t.as_str(), uuid);
+        }
+        self.hashes.insert(uuid, hash)?;
+        Ok(uuid)
+    }
+
+    /// Where the data of new records is written while it's received, see `RecordEncoder`.
+    fn spool_dir(&self) -> PathBuf {
+        self.path.join(".spool")
+    }
+
+    /// The data of a new record as it must be stored, if it's stored encoded. See
+    /// `VennbaseConfig::compression` and `VennbaseConfig::encryption`.
+    fn encode_record(&self, id: &uuid::Uuid, mimetype: &MimeType, data: &[u8]) -> Option<(RecordEncoding, Vec<u8>)> {
+        let mut encoding = RecordEncoding::default();
+        let compressed = should_compress(&self.compression, mimetype).then(|| compress(data)).flatten();
+        encoding.compressed = compressed.is_some();
+        let encrypted = match &self.encryption {
+            Some(keys) if keys.should_encrypt(mimetype) => Some(keys.encrypt(id, compressed.as_deref().unwrap_or(data))),
+            _ => None,
+        };
+        encoding.encrypted = encrypted.is_some();
+        Some((encoding, encrypted.or(compressed)?))
+    }
+
+    /// Whether new records of a mimetype are stored encrypted.
+    fn should_encrypt(&self, mimetype: &MimeType) -> bool {
+        self.encryption.as_ref().is_some_and(|keys| keys.should_encrypt(mimetype))
+    }
+
+    /// The partition where a new record of the given mimetype goes, invalidating the
+    /// cached queries that it would change.
+    fn partition_for_new_record(&mut self, mimetype: &MimeType) -> io::Result<&mut Partition> {
+        if self.partitions.contains_key(mimetype) {
+            self.invalidate_cached_queries(mimetype, None);
+        }
+        else {
+            // Cached queries don't know whether they would scan the new partition
+            self.query_cache.clear();
+        }
+        self.get_mut_or_create_partition(mimetype)
+    }
+
+    /// Starts a multi-part upload, returning its id.
+    ///
+    /// Without a mimetype, it's detected from the first part when the upload is committed.
+    pub fn begin_upload(&mut self, mimetype: Option<&MimeType>, tags: Vec<String>) -> io::Result<uuid::Uuid> {
+        self.uploads.begin(mimetype.cloned(), tags)
+    }
+
+    /// Prepares the encoding of a part of an upload, so it can be received without holding
+    /// the database lock. See `store_upload_part`.
+    ///
+    /// Only one byte more than `VennbaseConfig::upload_chunk_size` is read from parts,
+    /// so the reader may not be exhausted when they are too big.
+    pub fn upload_encoder(&self, id: &uuid::Uuid, part: u32) -> Result<RecordEncoder, UploadError> {
+        let upload = self.uploads.get(id).ok_or(UploadError::NotFound(*id))?;
+        if part == 0 {
+            return Err(UploadError::InvalidPart("parts are numbered from 1".to_owned()));
+        }
+        // The mimetype of uploads without one is only known when they are committed
+        let encoding = RecordEncoding {
+            encrypted: self.encryption.is_some()
+                && upload.mimetype.as_ref().is_none_or(|mimetype| self.should_encrypt(mimetype)),
+            ..Default::default()
+        };
+        let encoder = RecordEncoder::new(uuid::Uuid::new_v4(), encoding, self.encryption.clone(), self.spool_dir());
+        Ok(encoder.with_limit(self.upload_chunk_size + 1))
+    }
+
+    /// Stores a spooled part of an upload, replacing the part if it was already uploaded.
+    /// Returns the size of the part.
+    ///
+    /// Parts bigger than `VennbaseConfig::upload_chunk_size` are rejected.
+    pub fn store_upload_part(&mut self, id: &uuid::Uuid, part: u32, mut spooled: SpooledRecord) -> Result<u64, UploadError> {
+        // The upload may have been committed or aborted while the part was received
+        if self.uploads.get(id).is_none() {
+            return Err(UploadError::NotFound(*id));
+        }
+        let chunk = spooled.id;
+        let encoding = spooled.encoding;
+        self.chunks.push_record_from_reader(chunk, spooled.reader(), encoding)?;
+        let record_info = self.chunks.get_record_information(&chunk).expect("to exist since it was just pushed");
+        self.shared_buffers.invalidate_record(&self.chunks, record_info);
+        let size = chunk_size(record_info);
+        if size > self.upload_chunk_size {
+            self.chunks.delete_record(&chunk)?;
+            return Err(UploadError::InvalidPart(format!(
+                "part {part} is bigger than the chunk size of {} bytes", self.upload_chunk_size
+            )));
+        }
+        if let Some(replaced) = self.uploads.set_part(id, part, chunk)? {
+            // Unreferenced chunks are reclaimed by the next compaction
+            self.chunks.delete_record(&replaced)?;
+        }
+        Ok(size)
+    }
+
+    /// The number and size of the parts of an upload received so far.
+    pub fn upload_status(&self, id: &uuid::Uuid) -> Result<Vec<(u32, u64)>, UploadError> {
+        let upload = self.uploads.get(id).ok_or(UploadError::NotFound(*id))?;
+        Ok(upload.parts
+            .iter()
+            .filter_map(|(part, chunk)| Some((*part, chunk_size(self.chunks.get_record_information(chunk)?))))
+            .collect())
+    }
+
+    /// Discards an upload along with its parts.
+    pub fn abort_upload(&mut self, id: &uuid::Uuid) -> Result<(), UploadError> {
+        let upload = self.uploads.remove(id)?.ok_or(UploadError::NotFound(*id))?;
+        for chunk in upload.parts.values() {
+            self.chunks.delete_record(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Saves the record made of the parts of an upload and returns its UUID.
+    ///
+    /// The parts must be numbered from 1 without gaps, and all of them but the last must
+    /// be as big as `VennbaseConfig::upload_chunk_size`. The record is checked and
+    /// deduplicated like the ones saved with `save_record`. If it's rejected, the upload
+    /// is kept so that its parts can be fixed.
+    pub fn commit_upload(&mut self, id: &uuid::Uuid) -> Result<uuid::Uuid, UploadError> {
+        let upload = self.uploads.get(id).ok_or(UploadError::NotFound(*id))?;
+        let chunks = upload.parts.values().copied().collect::<Vec<_>>();
+        if upload.parts.is_empty() {
+            return Err(UploadError::MissingPart(1));
+        }
+        // Parts are sorted, so the first one out of place is right after a gap
+        if let Some((missing, _)) = (1..).zip(upload.parts.keys()).find(|(n, part)| n != *part) {
+            return Err(UploadError::MissingPart(missing));
+        }
+        for (part, chunk) in upload.parts.iter().rev().skip(1) {
+            let size = chunk_size(self.chunks.get_record_information(chunk).expect("to exist while the upload is pending"));
+            if size != self.upload_chunk_size {
+                return Err(UploadError::InvalidPart(format!(
+                    "part {part} has {size} bytes, only the last part can be smaller than {} bytes",
+                    self.upload_chunk_size
+                )));
+            }
+        }
+
+        let mut head = Vec::new();
+        self.chunks_reader(&chunks)?.take(SNIFFED_HEAD_LENGTH).read_to_end(&mut head)?;
+        let mimetype = match &upload.mimetype {
+            Some(mimetype) => mimetype.clone(),
+            None => MimeType::from(detect_mime_type(&head)).expect("detected mimetypes to be valid"),
+        };
+        let mimetype = self.validate_record_head(&mimetype, &head)?;
+        // Images are loaded anyway to read their metadata and generate their variants
+        let (data, hash) = if is_resizable_format(&mimetype) {
+            let mut data = Vec::new();
+            self.chunks_reader(&chunks)?.read_to_end(&mut data)?;
+            let (mimetype, image) = self.validate_record(&mimetype, &data)?;
+            let hash = content_hash(&data);
+            (Some((mimetype, data, image)), hash)
+        }
+        else {
+            let mut reader = HashingReader::new(self.chunks_reader(&chunks)?);
+            io::copy(&mut reader, &mut io::sink())?;
+            (None, reader.finish())
+        };
+        let mimetype = data.as_ref().map_or(mimetype, |(mimetype, _, _)| mimetype.clone());
+
+        let upload = self.uploads.remove(id)?.expect("to exist since it was just read");
+        let duplicate = self.find_duplicate(&mimetype, &hash).filter(|_| self.dedup != DedupMode::Off);
+        if duplicate.is_some() {
+            for chunk in &chunks {
+                self.chunks.delete_record(chunk)?;
+            }
+        }
+        match (self.dedup, duplicate) {
+            (DedupMode::Existing, Some(existing)) => return Ok(self.reference_record(&existing, upload.tags)?),
+            (DedupMode::Alias, Some(existing)) => return Ok(self.save_alias(&mimetype, &existing, hash, upload.tags)?),
+            _ => (),
+        }
+
+        let manifest = chunks.iter().flat_map(|chunk| *chunk.as_bytes()).collect::<Vec<_>>();
+        let uuid = self.partition_for_new_record(&mimetype)?.push_chunked_record(uuid::Uuid::new_v4(), &manifest)?;
+        let partition = &self.partitions[&mimetype];
+        let record_info = partition.get_record_information(&uuid).expect("to exist since it was just pushed");
+        self.shared_buffers.invalidate_record(partition, record_info);
+        for t in upload.tags {
+            self.tags.add_tag(t.as_str(), uuid);
+        }
+        if let Some((_, data, image)) = data {
+            self.store_metadata(&uuid, &mimetype, &data, image.as_ref())?;
+            self.generate_variants(&uuid, &mimetype, &data, image.as_ref())?;
+        }
+        self.hashes.insert(uuid, hash)?;
+        Ok(uuid)
+    }
+
+    /// The chunks that hold the data of a chunked record, in order.
+    fn manifest_chunks(&self, partition: &Partition, record_info: &RecordInformation) -> io::Result<Vec<uuid::Uuid>> {
+        let manifest = partition.read_at(record_info.start(), record_info.size())?;
+        Ok(manifest
+            .chunks_exact(16)
+            .map(|id| uuid::Uuid::from_slice(id).expect("to be 16 bytes long"))
+            .collect())
+    }
+
+    fn chunks_reader(&self, chunks: &[uuid::Uuid]) -> io::Result<ChunksReader> {
+        let chunks = chunks
+            .iter()
+            .map(|id| match self.chunks.get_record_information(id) {
+                Some(chunk) if chunk.is_active() => Ok(Chunk {
+                    id: *id,
+                    start: chunk.start(),
+                    size: chunk.size(),
+                    encrypted: chunk.encoding().encrypted,
+                }),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Missing chunk {id}"))),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        ChunksReader::new(self.chunks.file_path(), chunks, self.encryption.clone())
+    }
+
+    /// An active record of the partition whose data has the given hash.
+    fn find_duplicate(&self, mimetype: &MimeType, hash: &str) -> Option<uuid::Uuid> {
+        let partition = self.partitions.get(mimetype)?;
+        self.hashes
+            .records_with_hash(hash)
+            .iter()
+            .find(|id| partition.contains_record(id))
+            .copied()
+    }
+
+    /// Returns an existing record instead of saving its data again, adding the new tags
+    /// to it. The record is kept until it's deleted as many times as it was saved.
+    fn reference_record(&mut self, id: &uuid::Uuid, tags: Vec<String>) -> io::Result<uuid::Uuid> {
+        self.hashes.add_reference(id)?;
+        for tag in tags {
+            self.add_tag(id, &tag);
+        }
+        Ok(*id)
+    }
+
+    /// Saves a new record that shares the data of an existing one, along with its
+    /// metadata and variants.
+    fn save_alias(
+        &mut self,
+        mimetype: &MimeType,
+        target: &uuid::Uuid,
+        hash: String,
+        tags: Vec<String>
+    ) -> io::Result<uuid::Uuid> {
+        self.invalidate_cached_queries(mimetype, None);
+        let partition = self.partitions.get_mut(mimetype).expect("to exist since it holds the duplicate");
+        let uuid = partition
+            .push_alias(uuid::Uuid::new_v4(), target)?
+            .expect("the duplicate to be active");
+        let record_info = partition.get_record_information(&uuid).expect("to exist since it was just pushed");
+        self.shared_buffers.invalidate_record(partition, record_info);
+        for t in tags {
+            self.tags.add_tag(t.as_str(), uuid);
+        }
+        if let Some(metadata) = self.metadata.get(target).cloned() {
+            self.metadata.insert(uuid, Some(metadata))?;
+        }
+        for profile in self.variant_profiles.keys() {
+            let id = variant_id(&uuid, profile);
+            // Missing variants are generated when they are requested
+            if self.variants.push_alias(id, &variant_id(target, profile))?.is_some() {
+                let record_info = self.variants.get_record_information(&id).expect("to exist since it was just pushed");
+                self.shared_buffers.invalidate_record(&self.variants, record_info);
             }
-            uuid
-        })
+        }
+        self.hashes.insert(uuid, hash)?;
+        Ok(uuid)
+    }
+
+    /// Deletes a record and its tags.
+    ///
+    /// The record data stays in the partition file until the next compaction. Records
+    /// returned by several deduplicated saves are only deleted along with their last
+    /// reference. Returns false if the record doesn't exist.
+    pub fn delete_record(&mut self, id: &uuid::Uuid) -> io::Result<bool> {
+        let mimetype = match self.find_record_partition(id) {
+            Some(mimetype) => mimetype.clone(),
+            None => return Ok(false),
+        };
+        if self.hashes.release(id)? {
+            return Ok(true);
+        }
+        let partition = self.partitions.get_mut(&mimetype).expect("to exist since it was just found");
+        if !partition.delete_record(id)? {
+            return Ok(false);
+        }
+        let record_info = partition.get_record_information(id).expect("to exist since it was just deleted");
+        self.shared_buffers.invalidate(partition.file_path(), record_info.header_start(), 1);
+        self.resize_cache.retain(|(uuid, _, _), _| uuid != id);
+        self.delete_variants(id)?;
+        self.metadata.remove(id)?;
+        self.hashes.remove(id)?;
+        let tags = self.tags.get_tags_for_id(id)
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        for tag in tags {
+            self.tags.remove_tag(&tag, *id);
+        }
+        self.invalidate_cached_queries(&mimetype, None);
+        Ok(true)
     }
 
-    pub fn delete_record(&mut self, id: &str) {
-        unimplemented!("Deleting record with id: {}", id);
+    /// Replaces the data of a record, keeping its id, partition and tags.
+    ///
+    /// Aliases of the record keep the old data. Callers check `validate_replacement` first.
+    /// Returns false if the record doesn't exist.
+    pub fn replace_record(&mut self, id: &uuid::Uuid, data: &[u8]) -> io::Result<bool> {
+        let image = self.find_record_partition(id)
+            .and_then(|mimetype| decode_record_image(mimetype, data))
+            .and_then(Result::ok);
+        self.replace_decoded_record(id, data, image.as_ref())
     }
 
-    pub fn replace_record(&mut self, id: &str, data: &[u8]) {
-        unimplemented!("Replacing record with id: {} with data: {:#?}", id, data.len());
+    /// Like `replace_record`, for an image that was already decoded by
+    /// `validate_replacement`.
+    pub fn replace_decoded_record(&mut self, id: &uuid::Uuid, data: &[u8], image: Option<&DynamicImage>) -> io::Result<bool> {
+        let mimetype = match self.find_record_partition(id) {
+            Some(mimetype) => mimetype.clone(),
+            None => return Ok(false),
+        };
+        let encoded = self.encode_record(id, &mimetype, data);
+        let partition = self.partitions.get_mut(&mimetype).expect("to exist since it was just found");
+        let old_header_start = partition
+            .get_record_information(id)
+            .expect("to exist since it was just found")
+            .header_start();
+        partition.delete_record(id)?;
+        match &encoded {
+            Some((encoding, encoded)) => partition.push_record_with_encoding(*id, encoded, *encoding)?,
+            None => partition.push_record_with_id(*id, data)?,
+        };
+
+        let record_info = partition.get_record_information(id).expect("to exist since it was just pushed");
+        self.shared_buffers.invalidate(partition.file_path(), old_header_start, 1);
+        self.shared_buffers.invalidate_record(partition, record_info);
+        self.resize_cache.retain(|(uuid, _, _), _| uuid != id);
+        self.delete_variants(id)?;
+        self.metadata.remove(id)?;
+        self.store_metadata(id, &mimetype, data, image)?;
+        self.generate_variants(id, &mimetype, data, image)?;
+        self.hashes.insert(*id, content_hash(data))?;
+        self.invalidate_cached_queries(&mimetype, None);
+        Ok(true)
     }
 
-    pub fn query_records(&self, query: &str) -> Result<Vec<(&MimeType, &uuid::Uuid)>, VennbaseError> {
-        let parsed_query = parse_query(query)
-            .map_err(|_| VennbaseError("Invalid query".into()))?;
-        // FIXME: this need to be optimized. maybe using the Shunting yard algorithm?
-        // https://en.wikipedia.org/wiki/Shunting_yard_algorithm
-        let mut matched_records = Vec::<(&MimeType, &uuid::Uuid)>::with_capacity(4); // lucky number
+    /// Adds a tag to an existing record. Returns false if the record doesn't exist.
+    pub fn add_tag(&mut self, id: &uuid::Uuid, tag: &str) -> bool {
+        let Some(mimetype) = self.find_record_partition(id).cloned() else {
+            return false;
+        };
+        self.tags.add_tag(tag, *id);
+        self.invalidate_cached_queries(&mimetype, Some(tag));
+        true
+    }
 
-        fn evaluate(db: &Vennbase, node: &ASTNode, mime: &MimeType, id: &uuid::Uuid) -> Result<bool, ()> {
-            match node {
-                ASTNode::Not { operand } => {
-                    Ok(!evaluate(db, operand, mime, id)?)
-                },
-                ASTNode::And { left, right } => {
-                    Ok(evaluate(db, left, mime, id)? && evaluate(db, right, mime, id)?)
-                },
-                ASTNode::Or { left, right } => {
-                    Ok(evaluate(db, left, mime, id)? || evaluate(db, right, mime, id)?)
+    /// Removes a tag from an existing record. Returns false if the record doesn't exist.
+    pub fn remove_tag(&mut self, id: &uuid::Uuid, tag: &str) -> bool {
+        let Some(mimetype) = self.find_record_partition(id).cloned() else {
+            return false;
+        };
+        self.tags.remove_tag(tag, *id);
+        self.invalidate_cached_queries(&mimetype, Some(tag));
+        true
+    }
+
+    /// Evaluates a query, returning the matching records.
+    ///
+    /// Results are cached by the normalized query, so equivalent queries share the same
+    /// entry. Writes invalidate the entries that scan the partition they touch.
+    pub fn query_records(&self, query: &str) -> Result<Arc<Vec<(MimeType, uuid::Uuid)>>, QueryError> {
+        let tree = self.parse_and_expand_query(query)?;
+        let key = normalize(&tree);
+        if let Some(cached) = self.query_cache.get(&key) {
+            return Ok(Arc::clone(&cached.records));
+        }
+
+        let scanned = self.partitions
+            .keys()
+            .filter(|mimetype| self.should_scan_partition(&tree, mimetype))
+            .cloned()
+            .collect();
+        let tags = tree.get_identifiers()
+            .into_iter()
+            .filter_map(|identifier| identifier.strip_prefix("tag:"))
+            .map(str::to_owned)
+            .collect();
+        let records = Arc::new(
+            self.matches_for(tree)
+                .map(|record| record.map(|(mimetype, uuid)| (mimetype.clone(), *uuid)))
+                .collect::<Result<Vec<_>, _>>()?
+        );
+        self.query_cache.save(key, Arc::new(CachedQuery { records: Arc::clone(&records), scanned, tags }));
+        Ok(records)
+    }
+
+    /// Lazily evaluates a query, yielding the matching records one by one.
+    ///
+    /// Unlike `query_records`, the matches are not materialized, so the caller can start
+    /// sending them before the whole database has been evaluated.
+    ///
+    /// The results are not cached.
+    pub fn iter_query_records(&self, query: &str) -> Result<QueryMatches<'_>, QueryError> {
+        Ok(self.matches_for(self.parse_and_expand_query(query)?))
+    }
+
+    fn matches_for(&self, tree: ASTNode) -> QueryMatches<'_> {
+        QueryMatches {
+            db: self,
+            tree,
+            partitions: self.partitions.iter(),
+            current: None,
+        }
+    }
+
+    /// Returns the mimetype of the partition that holds an active record.
+    pub fn find_record_partition(&self, id: &uuid::Uuid) -> Option<&MimeType> {
+        self.partitions
+            .iter()
+            .find(|(_, partition)| partition.contains_record(id))
+            .map(|(mimetype, _)| mimetype)
+    }
+
+    /// Evicts the cached queries that scan the given partition. If a tag is given, only
+    /// the queries that reference it are evicted.
+    fn invalidate_cached_queries(&self, mimetype: &MimeType, tag: Option<&str>) {
+        self.query_cache.retain(|_, cached| {
+            let is_affected = cached.scanned.contains(mimetype)
+                && tag.is_none_or(|tag| cached.tags.iter().any(|t| t == tag));
+            !is_affected
+        });
+    }
+
+    /// Returns the plan that `query_records` would follow for the given query, without
+    /// evaluating it.
+    pub fn explain_query(&self, query: &str) -> Result<QueryPlan, QueryError> {
+        let parsed_query = self.parse_and_expand_query(query)?;
+
+        let mut scanned = Vec::new();
+        let mut skipped = Vec::new();
+        for (mimetype, partition) in &self.partitions {
+            let active_records = partition.iter_active_records().count();
+            if self.should_scan_partition(&parsed_query, mimetype) {
+                scanned.push((mimetype.to_string(), active_records));
+            }
+            else {
+                skipped.push((mimetype.to_string(), active_records));
+            }
+        }
+        scanned.sort();
+        skipped.sort();
+
+        let mut identifiers = parsed_query.get_identifiers().into_iter().collect::<Vec<_>>();
+        identifiers.sort();
+        let lookups = identifiers.into_iter().map(|identifier| {
+            match identifier.split_once(':') {
+                Some(("tag", tag)) if !tag.is_empty() => IndexLookup::Tag {
+                    tag: tag.to_owned(),
+                    cardinality: match tag {
+                        "*" => self.partitions.values().map(|p| p.iter_active_records().count()).sum(),
+                        tag => self.tags.map.get(tag).map_or(0, Vec::len),
+                    },
                 },
-                ASTNode::Implies { left, right } => {
-                    Ok(!evaluate(db, left, mime, id)? || evaluate(db, right, mime, id)?)
+                Some(("id", id)) if !id.is_empty() => IndexLookup::Id {
+                    id: id.to_owned(),
+                    cardinality: self.partitions
+                        .values()
+                        .flat_map(Partition::iter_active_records)
+                        .filter(|(uuid, _)| id == "*" || uuid.to_string() == id)
+                        .count(),
                 },
-                ASTNode::IfAndOnlyIf { left, right } => {
-                    Ok(evaluate(db, left, mime, id)? == evaluate(db, right, mime, id)?)
+                Some(("mime", pattern)) if !pattern.is_empty() => IndexLookup::Mime {
+                    pattern: pattern.to_owned(),
+                    cardinality: self.partitions
+                        .iter()
+                        .filter(|(mimetype, _)| mimetype.matches(pattern))
+                        .map(|(_, partition)| partition.iter_active_records().count())
+                        .sum(),
                 },
-                ASTNode::Literal { value } => {
-                    Ok(*value)
+                Some(("orientation", orientation)) if !orientation.is_empty() => IndexLookup::Metadata {
+                    identifier: identifier.to_owned(),
+                    cardinality: self.metadata.records
+                        .values()
+                        .filter(|metadata| orientation == "*" || metadata.orientation() == orientation)
+                        .count(),
                 },
-                ASTNode::Identifier { name: expression } => {
-                    let colon_i = expression.find(':').ok_or(())?;
-                    if colon_i == 0 || colon_i == expression.len() - 1 {
-                        // FIXME: improve error granularity
-                        // is this check really needed? (expression.rfind(':').unwrap() != colon_i)
-                        return Err(());
-                    }
-                    // Due to the checks, `filter` and `name` must be valid strings at this point
-                    let (filter_name, filter) = expression.split_at(colon_i + 1);
-
-                    let result = match filter_name {
-                        "mime:" => {
-                            filter == "*" || filter == mime.as_str()
-                        },
-                        "id:" => {
-                            filter == "*" || filter == id.to_string()
-                        },
-                        "tag:" => {
-                            filter == "*" || db.tags.map.get(filter).map_or(false, |records| {
-                                records.contains(&id.to_string())
+                _ => match parse_comparison(identifier) {
+                    Some((field, comparison, value)) => IndexLookup::Metadata {
+                        identifier: identifier.to_owned(),
+                        cardinality: self.metadata.records
+                            .values()
+                            .filter(|metadata| {
+                                let field_value = if field == "width" { metadata.width } else { metadata.height };
+                                comparison.holds(field_value, value)
                             })
-                        },
-                        _ => {
-                            return Err(());
-                        }
+                            .count(),
+                    },
+                    None => IndexLookup::Unknown { identifier: identifier.to_owned() },
+                },
+            }
+        }).collect();
+
+        Ok(QueryPlan {
+            normalized: normalize(&parsed_query),
+            scanned,
+            skipped,
+            lookups,
+        })
+    }
+
+    /// Saves a query as a view, so it can be referenced from other queries as `view:<name>`.
+    ///
+    /// The query must be valid, and the views it references must already exist.
+    pub fn create_view(&mut self, name: &str, query: &str) -> Result<(), ViewError> {
+        self.parse_and_expand_query(query)?;
+        self.views.create(name, query)
+    }
+
+    pub fn drop_view(&mut self, name: &str) -> Result<(), ViewError> {
+        self.views.drop_view(name)
+    }
+
+    pub fn list_views(&self) -> Vec<(&str, &str)> {
+        self.views.list()
+    }
+
+    /// Parses a query replacing every `view:<name>` identifier by the query of the view.
+    fn parse_and_expand_query(&self, query: &str) -> Result<ASTNode, QueryError> {
+        let tree = parse_query_checked(query, |identifier| {
+            match identifier.strip_prefix("view:") {
+                Some(name) if self.views.get(name).is_none() => Err((
+                    "view:".len(),
+                    QueryErrorKind::UnknownView,
+                    format!("view '{name}' doesn't exist")
+                )),
+                _ => Ok(()),
+            }
+        })?;
+        self.expand_views(tree, &mut Vec::new())
+    }
+
+    fn expand_views(&self, tree: ASTNode, stack: &mut Vec<String>) -> Result<ASTNode, QueryError> {
+        let expand = |node: Box<ASTNode>, stack: &mut Vec<String>| {
+            self.expand_views(*node, stack).map(Box::new)
+        };
+        Ok(match tree {
+            ASTNode::Identifier { name } => match name.strip_prefix("view:") {
+                Some(view) => {
+                    // Errors inside stored views have no position in the client query
+                    let view_error = |kind, message| QueryError {
+                        kind,
+                        offset: 0,
+                        message: format!("in view '{view}': {message}"),
                     };
-                    Ok(result)
+                    if stack.iter().any(|v| v == view) {
+                        return Err(view_error(QueryErrorKind::UnknownView, "the view references itself".into()));
+                    }
+                    let query = self.views.get(view).ok_or_else(|| {
+                        view_error(QueryErrorKind::UnknownView, "the view doesn't exist".into())
+                    })?;
+                    let tree = parse_query(query).map_err(|e| view_error(e.kind, e.message))?;
+                    stack.push(view.to_owned());
+                    let expanded = self.expand_views(tree, stack)?;
+                    stack.pop();
+                    expanded
                 },
+                None => ASTNode::Identifier { name },
+            },
+            ASTNode::Literal { value } => ASTNode::Literal { value },
+            ASTNode::Not { operand } => ASTNode::Not { operand: expand(operand, stack)? },
+            ASTNode::And { left, right } => ASTNode::And {
+                left: expand(left, stack)?,
+                right: expand(right, stack)?,
+            },
+            ASTNode::Or { left, right } => ASTNode::Or {
+                left: expand(left, stack)?,
+                right: expand(right, stack)?,
+            },
+            ASTNode::Implies { left, right } => ASTNode::Implies {
+                left: expand(left, stack)?,
+                right: expand(right, stack)?,
+            },
+            ASTNode::IfAndOnlyIf { left, right } => ASTNode::IfAndOnlyIf {
+                left: expand(left, stack)?,
+                right: expand(right, stack)?,
+            },
+        })
+    }
+
+    /// Decides whether a partition can contain records matching the query.
+    ///
+    /// The `mime:` propositions have a fixed value for every record of the partition, so only
+    /// the rest of them are permutated. If no permutation satisfies the query, the partition
+    /// can be skipped.
+    fn should_scan_partition(&self, tree: &ASTNode, mimetype: &MimeType) -> bool {
+        let mut identifiers = tree.get_identifiers().into_iter().collect::<Vec<_>>();
+        identifiers.sort();
+
+        let variables = identifiers.iter().map(|identifier| {
+            match identifier.strip_prefix("mime:") {
+                Some(pattern) if !pattern.is_empty() => Fixed(mimetype.matches(pattern)),
+                _ => Fickle,
             }
+        }).collect::<Vec<_>>();
+
+        // Permutating is exponential, so for big queries it's cheaper to just scan
+        if variables.iter().filter(|v| matches!(v, Fickle)).count() > MAX_FICKLE_PROPOSITIONS {
+            return true;
         }
 
-        // let variables = parsed_query.get_identifiers();
-        // if !variables.iter().any(|v| v.contains("mime:")) {
-            // Evaluate each record on every partition. MimeType doesnt matter
-        for (mimetype, partition) in &self.partitions {
-            for (uuid, _) in partition.iter_active_records() {
-                let matches = evaluate(self, &parsed_query, mimetype, uuid)
-                    .map_err(|_| VennbaseError("Failed to evaluate".into()))?;
-                if matches {
-                    matched_records.push((mimetype, uuid));
+        VariablesPermutations::new(&variables).any(|permutation| {
+            let values = identifiers
+                .iter()
+                .map(|identifier| identifier.to_string())
+                .zip(permutation)
+                .collect::<HashMap<String, bool>>();
+            evaluate(tree, &values).unwrap_or(true)
+        })
+    }
+
+    fn evaluate_for_record(&self, node: &ASTNode, mime: &MimeType, id: &uuid::Uuid) -> Result<bool, ()> {
+        match node {
+            ASTNode::Not { operand } => {
+                Ok(!self.evaluate_for_record(operand, mime, id)?)
+            },
+            ASTNode::And { left, right } => {
+                Ok(self.evaluate_for_record(left, mime, id)? && self.evaluate_for_record(right, mime, id)?)
+            },
+            ASTNode::Or { left, right } => {
+                Ok(self.evaluate_for_record(left, mime, id)? || self.evaluate_for_record(right, mime, id)?)
+            },
+            ASTNode::Implies { left, right } => {
+                Ok(!self.evaluate_for_record(left, mime, id)? || self.evaluate_for_record(right, mime, id)?)
+            },
+            ASTNode::IfAndOnlyIf { left, right } => {
+                Ok(self.evaluate_for_record(left, mime, id)? == self.evaluate_for_record(right, mime, id)?)
+            },
+            ASTNode::Literal { value } => {
+                Ok(*value)
+            },
+            ASTNode::Identifier { name: expression } => {
+                if let Some((field, comparison, value)) = parse_comparison(expression) {
+                    // Records without metadata (like non-image ones) never match
+                    let Some(metadata) = self.metadata.get(id) else {
+                        return Ok(false);
+                    };
+                    let field_value = match field {
+                        "width" => metadata.width,
+                        "height" => metadata.height,
+                        _ => return Err(()),
+                    };
+                    return Ok(comparison.holds(field_value, value));
                 }
-            }
-        }
-        Ok(matched_records)
-        // }
-
-        // At this point, since the query contains some MimeType criteria, we have the
-        // advantage of determining which partitions to skip
-        // NOTE: this has no advantage for small partitions.
-        // for (mimetype, partition) in &self.partitions {
-        //     let should_evaluate = false;
-        //     // Decide if we should skip the partition based on its mimetype
-
-        //     // in order to permutate this correctly, we should have:
-        //     // HashMap<String, VariablePermutation>
-        //     let variables_table = fix_boolean_values_for_mimetype(variables, mimetype);
-        //     // variable table should make mimetypes fickle!!!
-
-        //     // if some permutation of the fickle permutation matches, then the partition
-        //     // should be evaluated.
-        //     for permutation in permutate_fickle(variables_table) {
-        //         // evaluate this permutation
-        //         if should_evaluate = evaluate_for_table(&parsed_query, &permutation).unwrap() {
-        //             break;
-        //         }
-        //     }
-
-        //     if should_evaluate {
-        //         for record in partition.iter_active_records() {
-        //             if evaluate_for_record(&parsed_query, &record, &mimetype) == true {
-        //                 matched_records.push(record);
-        //             }
-        //         }
-        //     }
-        // }
+                // Identifiers were already validated by `parse_query`, so this is just a
+                // safety net
+                let colon_i = expression.find(':').ok_or(())?;
+                if colon_i == 0 || colon_i == expression.len() - 1 {
+                    return Err(());
+                }
+                // Due to the checks, `filter` and `name` must be valid strings at this point
+                let (filter_name, filter) = expression.split_at(colon_i + 1);
 
+                let result = match filter_name {
+                    "mime:" => {
+                        mime.matches(filter)
+                    },
+                    "id:" => {
+                        filter == "*" || filter == id.to_string()
+                    },
+                    "tag:" => {
+                        filter == "*" || self.tags.map.get(filter).is_some_and(|records| {
+                            records.contains(&id.to_string())
+                        })
+                    },
+                    "orientation:" => {
+                        self.metadata.get(id).is_some_and(|metadata| {
+                            filter == "*" || metadata.orientation() == filter
+                        })
+                    },
+                    _ => {
+                        return Err(());
+                    }
+                };
+                Ok(result)
+            },
+        }
     }
 
     pub fn fetch_record_by_id(
         &self,
         record_id: &uuid::Uuid,
-        resize_dims: &Option<Dimensions>
-    ) -> io::Result<Option<(&MimeType, StoredRecord)>> {
+        resize: &Option<ResizeOptions>
+    ) -> io::Result<Option<(MimeType, StoredRecord)>> {
         for (mimetype, partition) in &self.partitions {
-            if let Some(mut record) = partition.fetch_record(record_id)? {
-                let size = record.limit();
-                // If we need to resize the image
-                if is_resizable_format(mimetype) && resize_dims.is_some() {
-                    let new_dimensions = resize_dims.as_ref().unwrap();
-                    // Load the entire image into memory
-                    let mut data = Vec::with_capacity(size as usize);
-                    record.read_to_end(&mut data)?;
-                    let resize_result = resize_image(
-                        &data,
-                        // MIMEtype should be valid at this point
-                        ImageFormat::from_mime_type(mimetype.as_str()).unwrap(),
-                        new_dimensions
-                    );
-                    let data = match resize_result {
-                        Ok(data) => data,
-                        Err(_) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "Failed to resize image"
-                            ));
-                        },
-                    };
+            let record_info = match partition.get_record_information(record_id) {
+                Some(record_info) if record_info.is_active() => record_info,
+                _ => continue,
+            };
 
-                    return Ok(
-                        Some((mimetype, StoredRecord::InMemoryRecord(data)))
-                    );
+            // If we need to resize the image
+            if is_resizable_format(mimetype) && resize.is_some() {
+                let options = resize.as_ref().unwrap();
+                // MIMEtype should be valid at this point
+                let format = ImageFormat::from_mime_type(mimetype.as_str()).unwrap();
+                // Converted images are served with the mimetype of their new format
+                let output_mimetype = match options.output {
+                    Some(output) => output.to_mime_type().into(),
+                    None => mimetype.clone(),
+                };
+                let key = (*record_id, options.clone(), format);
+                if let Some(data) = self.resize_cache.get(&key) {
+                    return Ok(Some((output_mimetype, StoredRecord::InMemoryRecord(data.to_vec()))));
                 }
-                // Otherwise, send the image as it is
+
+                // Load the entire image into memory
+                let Some(data) = self.read_record_data(partition, record_id, record_info)? else {
+                    continue;
+                };
+                let resize_result = resize_image(&data, format, options);
+                let data = match resize_result {
+                    Ok(data) => data,
+                    Err(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Failed to resize image"
+                        ));
+                    },
+                };
+                self.resize_cache.save(key, Arc::new(data.clone()));
+
                 return Ok(
-                    Some((mimetype, StoredRecord::InDiskRecord(record)))
+                    Some((output_mimetype, StoredRecord::InMemoryRecord(data)))
                 );
             }
+            // Otherwise, send the image as it is
+            if let Some(record) = self.read_record(partition, record_id, record_info)? {
+                return Ok(Some((mimetype.clone(), record)));
+            }
         }
         Ok(None)
     }
 
+    /// Whether a record is stored compressed, see `VennbaseConfig::compression`.
+    pub fn is_record_compressed(&self, record_id: &uuid::Uuid) -> bool {
+        self.find_record_partition(record_id)
+            .and_then(|mimetype| self.partitions[mimetype].get_record_information(record_id))
+            .is_some_and(|record_info| record_info.encoding().compressed)
+    }
+
+    /// Fetches the gzip stream of a compressed record without decompressing it.
+    ///
+    /// Returns `None` if the record doesn't exist or it's not compressed.
+    pub fn fetch_compressed_record(&self, record_id: &uuid::Uuid) -> io::Result<Option<(MimeType, StoredRecord)>> {
+        let Some(mimetype) = self.find_record_partition(record_id) else {
+            return Ok(None);
+        };
+        let partition = &self.partitions[mimetype];
+        let record_info = partition.get_record_information(record_id).expect("to exist since it was just found");
+        if !record_info.encoding().compressed {
+            return Ok(None);
+        }
+        let Some(record) = self.read_decrypted_record(partition, record_id, record_info)? else {
+            return Ok(None);
+        };
+        // The trailer is only meaningful to vennbase
+        let end = record.size().saturating_sub(TRAILER_SIZE_BYTES + 1);
+        Ok(Some((mimetype.clone(), record.into_range(0, end)?)))
+    }
+
+    /// Reads the data of a record, decoding it if it's stored encoded.
+    fn read_record(
+        &self,
+        partition: &Partition,
+        record_id: &uuid::Uuid,
+        record_info: &RecordInformation
+    ) -> io::Result<Option<StoredRecord>> {
+        match self.read_decrypted_record(partition, record_id, record_info)? {
+            Some(record) if record_info.encoding().compressed => {
+                Ok(Some(StoredRecord::Decompressed(DecompressedRecord::new(record)?)))
+            },
+            record => Ok(record),
+        }
+    }
+
+    /// Reads the data of a record as it's stored, decrypting it if it's encrypted.
+    fn read_decrypted_record(
+        &self,
+        partition: &Partition,
+        record_id: &uuid::Uuid,
+        record_info: &RecordInformation
+    ) -> io::Result<Option<StoredRecord>> {
+        let record = self.read_stored_record(partition, record_id, record_info)?;
+        if !record_info.encoding().encrypted {
+            return Ok(record);
+        }
+        let keys = self.encryption.as_ref().ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Record {record_id} is encrypted, but no key is configured")
+        ))?;
+        let Some(record) = record else {
+            return Ok(None);
+        };
+        let owner = partition.data_owner(record_id, record_info)?;
+        Ok(Some(StoredRecord::Decrypted(keys.decrypt_record(&owner, record)?)))
+    }
+
+    /// Reads the data of a record as it's stored. Small records are served from the
+    /// shared buffers, big ones are streamed from the disk.
+    fn read_stored_record(
+        &self,
+        partition: &Partition,
+        record_id: &uuid::Uuid,
+        record_info: &RecordInformation
+    ) -> io::Result<Option<StoredRecord>> {
+        if record_info.is_chunked() {
+            let chunks = self.manifest_chunks(partition, record_info)?;
+            return Ok(Some(StoredRecord::InDiskChunks(self.chunks_reader(&chunks)?)));
+        }
+        if self.shared_buffers.can_buffer(record_info.size()) {
+            let data = self.shared_buffers.read(partition, record_info.start(), record_info.size())?;
+            return Ok(Some(StoredRecord::InMemoryRecord(data)));
+        }
+        Ok(partition.fetch_record(record_id)?.map(StoredRecord::InDiskRecord))
+    }
+
+    /// Reads and stores the metadata of a freshly saved image.
+    ///
+    /// Images that can't be decoded are kept without metadata.
+    fn store_metadata(
+        &mut self,
+        record_id: &uuid::Uuid,
+        mimetype: &MimeType,
+        data: &[u8],
+        image: Option<&DynamicImage>
+    ) -> io::Result<()> {
+        if !is_resizable_format(mimetype) {
+            return Ok(());
+        }
+        self.metadata.insert(*record_id, read_image_metadata(record_id, mimetype, data, image))
+    }
+
+    /// Images without metadata or without a perceptual hash, that were not found to be
+    /// undecodable yet.
+    fn records_missing_metadata(&self) -> Vec<(MimeType, uuid::Uuid)> {
+        self.partitions
+            .iter()
+            .filter(|(mimetype, _)| is_resizable_format(mimetype))
+            .flat_map(|(mimetype, partition)| {
+                partition.iter_active_records().map(move |(id, _)| (mimetype.clone(), *id))
+            })
+            .filter(|(_, id)| !self.metadata.is_undecodable(id))
+            .filter(|(_, id)| self.metadata.get(id).is_none_or(|metadata| metadata.dhash.is_none()))
+            .collect()
+    }
+
+    /// Reads the metadata of the images saved before metadata was supported, and hashes
+    /// the images saved before perceptual hashes were. The metadata file is written once
+    /// all of them are read.
+    fn backfill_metadata(&mut self) -> io::Result<()> {
+        let missing = self.records_missing_metadata();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        for (mimetype, id) in missing {
+            let partition = &self.partitions[&mimetype];
+            let record_info = partition.get_record_information(&id).expect("to exist since it was just listed");
+            if let Some(data) = self.read_record_data(partition, &id, record_info)? {
+                let image = decode_record_image(&mimetype, &data).and_then(Result::ok);
+                self.metadata.insert_unflushed(id, read_image_metadata(&id, &mimetype, &data, image.as_ref()));
+            }
+        }
+        self.metadata.flush_data()
+    }
+
+    /// Finds the images whose perceptual hash is within `threshold` bits of the hash of
+    /// the given record, sorted from the most similar one.
+    ///
+    /// Returns `None` if the record has no hash, because it's not an image.
+    pub fn similar_records(&self, record_id: &uuid::Uuid, threshold: u32) -> Option<Vec<(&MimeType, uuid::Uuid)>> {
+        let hash = self.metadata.get(record_id)?.dhash?;
+        let mut similar = self.metadata.records
+            .iter()
+            .filter(|(id, _)| *id != record_id)
+            .filter_map(|(id, metadata)| Some((hamming_distance(hash, metadata.dhash?), *id)))
+            .filter(|(distance, _)| *distance <= threshold)
+            .collect::<Vec<_>>();
+        similar.sort();
+        Some(similar
+            .into_iter()
+            .filter_map(|(_, id)| Some((self.find_record_partition(&id)?, id)))
+            .collect())
+    }
+
+    /// The etag of a record as served by `get`.
+    ///
+    /// It's the hash of the record data, or a hash derived from it and the options of the
+    /// image for variants, resized, converted and stripped images, and for compressed
+    /// records served as they are stored. Since the options include the encoder settings,
+    /// etags change along with the configuration.
+    pub fn record_etag(
+        &self,
+        record_id: &uuid::Uuid,
+        variant: Option<&str>,
+        resize: &Option<ResizeOptions>,
+        strip_exif: bool,
+        raw: bool
+    ) -> Option<String> {
+        let hash = self.hashes.get(record_id)?;
+        // Raw records are served as they are stored, whatever the other options say
+        let representation = match (variant, resize) {
+            _ if raw => "raw=1".to_owned(),
+            (Some(profile), _) => format!("@{profile} {:?}", self.variant_profiles.get(profile)?),
+            (None, Some(options)) => format!("{options:?}"),
+            (None, None) if strip_exif => "strip=exif".to_owned(),
+            (None, None) => return Some(hash.clone()),
+        };
+        Some(content_hash(format!("{hash} {representation}").as_bytes()))
+    }
+
+    /// Hashes the records saved before content hashes were computed.
+    fn backfill_content_hashes(&mut self) -> io::Result<()> {
+        let missing = self.partitions
+            .iter()
+            .flat_map(|(mimetype, partition)| {
+                partition.iter_active_records().map(move |(id, _)| (mimetype.clone(), *id))
+            })
+            .filter(|(_, id)| self.hashes.get(id).is_none())
+            .collect::<Vec<_>>();
+        for (mimetype, id) in missing {
+            let partition = &self.partitions[&mimetype];
+            let record_info = partition.get_record_information(&id).expect("to exist since it was just listed");
+            if let Some(data) = self.read_record_data(partition, &id, record_info)? {
+                self.hashes.insert(id, content_hash(&data))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites every partition without its deleted records, returning the number of
+    /// bytes reclaimed. Data shared by deduplicated records is kept while any of them is
+    /// active, and so are the chunks of the active chunked records and pending uploads.
+    ///
+    /// When encryption is enabled, this is also how keys are rotated: records and side
+    /// files encrypted with a previous key are encrypted again with the current one, and
+    /// the records saved before their mimetype was encrypted are encrypted.
+    pub fn compact(&mut self) -> io::Result<u64> {
+        let mut referenced = self.uploads.chunk_ids().copied().collect::<HashSet<_>>();
+        // Variants and chunks of the records that must be encrypted
+        let mut must_encrypt = self.uploads.uploads
+            .values()
+            .filter(|upload| upload.mimetype.as_ref().is_none_or(|mimetype| self.should_encrypt(mimetype)))
+            .flat_map(|upload| upload.parts.values().copied())
+            .collect::<HashSet<_>>();
+        for (mimetype, partition) in &self.partitions {
+            let encrypted = self.should_encrypt(mimetype);
+            for (id, record_info) in partition.iter_active_records() {
+                if record_info.is_chunked() {
+                    let chunks = self.manifest_chunks(partition, record_info)?;
+                    if encrypted {
+                        must_encrypt.extend(&chunks);
+                    }
+                    referenced.extend(chunks);
+                }
+                if encrypted {
+                    must_encrypt.extend(self.variant_profiles.keys().map(|profile| variant_id(id, profile)));
+                }
+            }
+        }
+        let unreferenced = self.chunks
+            .iter_active_records()
+            .map(|(id, _)| *id)
+            .filter(|id| !referenced.contains(id))
+            .collect::<Vec<_>>();
+        for id in unreferenced {
+            self.chunks.delete_record(&id)?;
+        }
+
+        let mut reclaimed = 0;
+        let keys = self.encryption.as_deref();
+        for (mimetype, partition) in self.partitions.iter_mut() {
+            let encrypted = keys.is_some_and(|keys| keys.should_encrypt(mimetype));
+            reclaimed += partition.compact_with(|partition, id, record_info| {
+                reencrypt_record(keys, partition, id, record_info, encrypted)
+            })?;
+            self.shared_buffers.invalidate_file(partition.file_path());
+        }
+        for partition in [&mut self.variants, &mut self.chunks] {
+            reclaimed += partition.compact_with(|partition, id, record_info| {
+                reencrypt_record(keys, partition, id, record_info, must_encrypt.contains(id))
+            })?;
+            self.shared_buffers.invalidate_file(partition.file_path());
+        }
+
+        if self.encryption.is_some() {
+            for file in [&self.tags.file, &self.views.file, &self.metadata.file, &self.hashes.file, &self.uploads.file] {
+                file.rewrite()?;
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// The partition, size and image metadata (if any) of a record.
+    pub fn record_information(&self, record_id: &uuid::Uuid) -> Option<(&MimeType, u64, Option<&ImageMetadata>)> {
+        let mimetype = self.find_record_partition(record_id)?;
+        let partition = &self.partitions[mimetype];
+        let record_info = partition.get_record_information(record_id)?;
+        let size = match record_info.encoding() == RecordEncoding::default() {
+            true => record_info.size(),
+            false => self.read_record(partition, record_id, record_info).ok()??.size(),
+        };
+        Some((mimetype, size, self.metadata.get(record_id)))
+    }
+
+    /// Reads a whole record into memory.
+    fn read_record_data(
+        &self,
+        partition: &Partition,
+        record_id: &uuid::Uuid,
+        record_info: &RecordInformation
+    ) -> io::Result<Option<Vec<u8>>> {
+        match self.read_record(partition, record_id, record_info)? {
+            Some(StoredRecord::InMemoryRecord(data)) => Ok(Some(data)),
+            Some(StoredRecord::InDiskRecord(mut reader)) => {
+                let mut data = Vec::with_capacity(record_info.size() as usize);
+                reader.read_to_end(&mut data)?;
+                Ok(Some(data))
+            },
+            Some(StoredRecord::InDiskChunks(mut reader)) => {
+                let mut data = Vec::with_capacity(reader.size() as usize);
+                reader.read_to_end(&mut data)?;
+                Ok(Some(data))
+            },
+            Some(StoredRecord::Decompressed(mut reader)) => {
+                let mut data = Vec::with_capacity(reader.size() as usize);
+                reader.read_to_end(&mut data)?;
+                Ok(Some(data))
+            },
+            Some(StoredRecord::Decrypted(mut reader)) => {
+                let mut data = Vec::with_capacity(reader.size() as usize);
+                reader.read_to_end(&mut data)?;
+                Ok(Some(data))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Fetches a record without its EXIF metadata, like GPS coordinates or the camera model.
+    ///
+    /// JPEG and PNG images are served without re-encoding them, unless a JPEG must be
+    /// rotated to be displayed upright without its orientation tag. Other images are
+    /// re-encoded, and records that are not images are returned as they are.
+    pub fn fetch_record_without_metadata(
+        &self,
+        record_id: &uuid::Uuid
+    ) -> io::Result<Option<(MimeType, StoredRecord)>> {
+        let mimetype = match self.find_record_partition(record_id) {
+            Some(mimetype) if is_resizable_format(mimetype) => mimetype,
+            Some(_) => return self.fetch_record_by_id(record_id, &None),
+            None => return Ok(None),
+        };
+        let partition = &self.partitions[mimetype];
+        let record_info = partition.get_record_information(record_id).expect("to exist since it was just found");
+        let Some(data) = self.read_record_data(partition, record_id, record_info)? else {
+            return Ok(None);
+        };
+
+        let stripped = match ImageFormat::from_mime_type(mimetype.as_str()) {
+            Some(ImageFormat::Jpeg) if jpeg_orientation(&data).unwrap_or(1) == 1 => strip_jpeg_metadata(&data),
+            Some(ImageFormat::Png) => strip_png_metadata(&data),
+            _ => None,
+        };
+        match stripped {
+            Some(data) => Ok(Some((mimetype.clone(), StoredRecord::InMemoryRecord(data)))),
+            // Re-encoded images don't carry any metadata
+            None => self.fetch_record_by_id(record_id, &Some(ResizeOptions {
+                dims: Dimensions::original(),
+                filter: self.resize_filter,
+                mode: ResizeMode::default(),
+                gravity: Gravity::default(),
+                output: None,
+                encoding: self.encoding,
+                first_frame: false,
+            })),
+        }
+    }
+
+    pub fn default_resize_filter(&self) -> ResizeFilter {
+        self.resize_filter
+    }
+
+    pub fn default_encoding(&self) -> EncodeOptions {
+        self.encoding
+    }
+
+    pub fn has_variant_profile(&self, name: &str) -> bool {
+        self.variant_profiles.contains_key(name)
+    }
+
+    /// Fetches a derived variant of a record, like `@thumb`.
+    ///
+    /// Variants of records saved before the profile was configured are generated on
+    /// demand. Records that can't be resized are returned as they are.
+    pub fn fetch_variant(
+        &mut self,
+        record_id: &uuid::Uuid,
+        profile: &str
+    ) -> io::Result<Option<(MimeType, StoredRecord)>> {
+        let mimetype = match self.find_record_partition(record_id) {
+            Some(mimetype) if is_resizable_format(mimetype) => mimetype.clone(),
+            Some(_) => {
+                return self.fetch_record_by_id(record_id, &None);
+            },
+            None => return Ok(None),
+        };
+        let options = self.variant_profiles.get(profile).ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Unknown variant profile"
+        ))?.clone();
+
+        let id = variant_id(record_id, profile);
+        if !self.variants.contains_record(&id) {
+            let partition = &self.partitions[&mimetype];
+            let record_info = partition.get_record_information(record_id).expect("to exist since it was just found");
+            let Some(data) = self.read_record_data(partition, record_id, record_info)? else {
+                return Ok(None);
+            };
+            let format = ImageFormat::from_mime_type(mimetype.as_str()).unwrap();
+            let variant = resize_image(&data, format, &options).map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Failed to resize image"
+            ))?;
+            self.push_variant(id, &mimetype, &variant)?;
+        }
+
+        let record_info = self.variants.get_record_information(&id).expect("to exist since it was just checked");
+        Ok(self.read_record(&self.variants, &id, record_info)?.map(|record| (mimetype, record)))
+    }
+
+    /// Generates and stores the configured variants of a freshly saved image, from the
+    /// image decoded when it was saved.
+    ///
+    /// Images that couldn't be decoded are kept without variants.
+    fn generate_variants(
+        &mut self,
+        record_id: &uuid::Uuid,
+        mimetype: &MimeType,
+        data: &[u8],
+        image: Option<&DynamicImage>
+    ) -> io::Result<()> {
+        let Some(image) = image.filter(|_| is_resizable_format(mimetype)) else {
+            return Ok(());
+        };
+        let format = ImageFormat::from_mime_type(mimetype.as_str()).unwrap();
+        let variants = self.variant_profiles
+            .iter()
+            .filter_map(|(profile, options)| match resize_decoded_image(image, data, format, options) {
+                Ok(variant) => Some((variant_id(record_id, profile), variant)),
+                Err(e) => {
+                    println!("Couldn't generate variant {profile} of {record_id}: {e:?}");
+                    None
+                },
+            })
+            .collect::<Vec<_>>();
+        for (id, variant) in variants {
+            self.push_variant(id, mimetype, &variant)?;
+        }
+        Ok(())
+    }
+
+    /// Stores a variant of a record, encrypted if the record mimetype is encrypted.
+    fn push_variant(&mut self, id: uuid::Uuid, mimetype: &MimeType, data: &[u8]) -> io::Result<()> {
+        match self.encryption.as_ref().filter(|keys| keys.should_encrypt(mimetype)) {
+            Some(keys) => {
+                let encoding = RecordEncoding { encrypted: true, ..Default::default() };
+                self.variants.push_record_with_encoding(id, &keys.encrypt(&id, data), encoding)?
+            },
+            None => self.variants.push_record_with_id(id, data)?,
+        };
+        let record_info = self.variants.get_record_information(&id).expect("to exist since it was just pushed");
+        self.shared_buffers.invalidate_record(&self.variants, record_info);
+        Ok(())
+    }
+
+    fn delete_variants(&mut self, record_id: &uuid::Uuid) -> io::Result<()> {
+        for profile in self.variant_profiles.keys() {
+            let id = variant_id(record_id, profile);
+            if self.variants.delete_record(&id)? {
+                let record_info = self.variants.get_record_information(&id).expect("to exist since it was just deleted");
+                self.shared_buffers.invalidate(self.variants.file_path(), record_info.header_start(), 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Usage statistics of the database caches, by name.
+    pub fn cache_stats(&self) -> Vec<(&'static str, CacheStats)> {
+        vec![
+            ("shared_buffers", self.shared_buffers.stats()),
+            ("query_cache", self.query_cache.stats()),
+            ("resize_cache", self.resize_cache.stats()),
+        ]
+    }
+
     pub fn get_tags_for_record(&self, record_id: &uuid::Uuid) -> Vec<&str> {
         self.tags.get_tags_for_id(record_id)
     }
 
-    fn parse_dir_tree(path: &str) -> io::Result<Vennbase> {
+    fn parse_dir_tree(path: &str, config: &VennbaseConfig) -> io::Result<Vennbase> {
         let dir = fs::read_dir(path)?;
         let mut partitions: HashMap<MimeType, Partition> = HashMap::new();
 
         for entry in dir {
             // Read the filename
             let filepath = entry?.path();
-            if filepath.is_dir() || filepath.file_name().unwrap() == ".map" {
+            // Partition names are base64 encoded, so they never start with a dot. Dot files
+            // are used for the database metadata (.map, .views, etc)
+            if filepath.is_dir() || filepath.file_name().unwrap().to_string_lossy().starts_with('.') {
                 continue;
             }
             let mimetype = MimeType::from_base64_filename(filepath.file_name().unwrap())?;
@@ -249,20 +1632,42 @@ impl Vennbase {
             );
         }
 
-        let mut tags_map = {
-            let map_path = PathBuf::from(path).join(".map");
-            let file = File::open(map_path)?;
-            let reader = BufReader::new(file);
-            serde_json::from_reader::<_, InvertedIndexMap>(reader)?
-        };
+        // Records that were being received when the process stopped were never saved
+        match fs::remove_dir_all(PathBuf::from(path).join(".spool")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
 
-        tags_map.path = PathBuf::from(path).join(".map");
+        let encryption = EncryptionKeys::load(&config.encryption)?;
+        let side_file = |name: &str| SideFile::new(PathBuf::from(path).join(name), encryption.clone());
+        let mut tags_map = side_file(".map").read_json::<InvertedIndexMap>()?;
+        tags_map.file = side_file(".map");
 
-        Ok(Vennbase {
+        let mut db = Vennbase {
             path: path.into(),
             partitions,
             tags: tags_map,
-        })
+            views: ViewsMap::from_file(side_file(".views"))?,
+            query_cache: new_query_cache(config),
+            shared_buffers: SharedBuffers::new(config.shared_buffers, config.max_buffered_record_size),
+            resize_cache: new_resize_cache(config),
+            variants: Partition::open_or_create(PathBuf::from(path).join(".variants"))?,
+            variant_profiles: parse_variant_profiles(config)?,
+            resize_filter: config.resize_filter,
+            encoding: config.encoding(),
+            metadata: MetadataMap::from_file(side_file(".meta"))?,
+            mime_validation: config.mime_validation,
+            hashes: ContentHashMap::from_file(side_file(".hashes"))?,
+            dedup: config.dedup,
+            chunks: Partition::open_or_create(PathBuf::from(path).join(".chunks"))?,
+            uploads: UploadsMap::from_file(side_file(".uploads"))?,
+            upload_chunk_size: config.upload_chunk_size,
+            compression: config.compression.clone(),
+            encryption,
+        };
+        db.backfill_metadata()?;
+        db.backfill_content_hashes()?;
+        Ok(db)
     }
 
     /// Creates a new partition for the database with the given Mime Type.
@@ -273,30 +1678,7 @@ impl Vennbase {
         let partition_path = self.path.join(mimetype.to_base64_pathname());
         assert!(!partition_path.exists());
         println!("New partition: {partition_path:?}");
-        // File creation is done with `write: true`, `create: true`, `truncate: true`
-        // So the only error we can get is either a permission error, or to a database
-        // doesn't exist error. Both are fatal.
-        let file = match File::create(&partition_path) {
-            Ok(file) => file,
-            Err(err) if err.kind() == io::ErrorKind::NotFound => todo!(),
-            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => todo!(),
-            Err(_) => unreachable!()
-        };
-        let mut writer = BufWriter::new(file);
-
-        let created_at = VennTimestamp::now();
-        let last_compaction = VennTimestamp::now();
-
-        // Not be able to write to the partition is considered fatal
-        writer.write_all(created_at.0.to_le_bytes().as_slice())?;
-        writer.write_all(last_compaction.0.to_le_bytes().as_slice())?;
-
-        let new_partition = Partition::new(
-            partition_path,
-            HashMap::new(),
-            created_at,
-            last_compaction
-        );
+        let new_partition = Partition::create(partition_path)?;
 
         // FIXME: we are performing two unnecessary lookups here
         self.partitions.insert(mimetype.clone(), new_partition);
@@ -315,8 +1697,238 @@ impl Vennbase {
             self.create_new_partition(mimetype.to_owned())
         }
     }
+}
+
+/// Helpers shared by the tests that need a database.
+#[cfg(test)]
+pub mod testing {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::features::resize::{EncodeOptions, Gravity, ResizeMode};
+
+    /// A path in the temporary directory that no other test uses.
+    pub fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("vennbase-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    /// A PNG image with a gradient, so images of different sizes have different pixels.
+    pub fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = image::RgbImage::from_fn(width, height, |x, y| image::Rgb([(x * 5) as u8, (y * 5) as u8, 0]));
+        let mut data = Vec::new();
+        image::DynamicImage::ImageRgb8(image)
+            .write_to(&mut io::Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    /// Resizes to `dims` with the default settings.
+    pub fn resize_options(dims: &str) -> ResizeOptions {
+        ResizeOptions {
+            dims: Dimensions::from_dim_str(dims).unwrap(),
+            filter: ResizeFilter::default(),
+            mode: ResizeMode::default(),
+            gravity: Gravity::default(),
+            output: None,
+            encoding: EncodeOptions::default(),
+            first_frame: false,
+        }
+    }
+
+    /// An empty database with the default configuration, see `temp_db_with`.
+    pub fn temp_db() -> io::Result<(Vennbase, PathBuf)> {
+        temp_db_with(&VennbaseConfig::default())
+    }
+
+    /// An empty database in a temporary directory, along with the directory.
+    pub fn temp_db_with(config: &VennbaseConfig) -> io::Result<(Vennbase, PathBuf)> {
+        let path = temp_path();
+        Ok((Vennbase::from_dir(path.to_str().unwrap(), config)?, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::{png, resize_options, temp_db, temp_db_with};
+
+    fn is_cached(db: &mut Vennbase, query: &str) -> bool {
+        db.query_cache.get(&query.to_owned()).is_some()
+    }
+
+    #[test]
+    fn editing_tags_only_evicts_the_queries_using_them() -> io::Result<()> {
+        let (mut db, path) = temp_db()?;
+        let mimetype = MimeType::from("text/plain").unwrap();
+        let tagged = db.save_record(&mimetype, b"tagged", vec!["a".to_owned()])?;
+        let untagged = db.save_record(&mimetype, b"untagged", vec!["b".to_owned()])?;
+
+        assert_eq!(db.query_records("tag:a").unwrap().len(), 1);
+        assert_eq!(db.query_records("tag:b").unwrap().len(), 1);
+        assert!(is_cached(&mut db, "tag:a") && is_cached(&mut db, "tag:b"));
+
+        assert!(db.add_tag(&untagged, "a"));
+        assert!(!is_cached(&mut db, "tag:a") && is_cached(&mut db, "tag:b"));
+        assert_eq!(db.query_records("tag:a").unwrap().len(), 2);
+
+        assert!(db.remove_tag(&tagged, "a"));
+        assert!(!is_cached(&mut db, "tag:a") && is_cached(&mut db, "tag:b"));
+        assert_eq!(*db.query_records("tag:a").unwrap(), vec![(mimetype, untagged)]);
+
+        fs::remove_dir_all(path)
+    }
+
+    /// Fetches a resized image, which is always served from memory.
+    fn fetch_resized(db: &Vennbase, id: &uuid::Uuid, dims: &str) -> Option<Vec<u8>> {
+        match db.fetch_record_by_id(id, &Some(resize_options(dims))).unwrap() {
+            Some((_, StoredRecord::InMemoryRecord(data))) => Some(data),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn replacing_a_record_evicts_its_resized_images() -> io::Result<()> {
+        let (mut db, path) = temp_db()?;
+        let id = db.save_record(&MimeType::from("image/png").unwrap(), &png(40, 20), vec![])?;
+
+        let resized = fetch_resized(&db, &id, "10x10").unwrap();
+        assert_eq!(image::load_from_memory(&resized).unwrap().width(), 10);
+        assert_eq!(fetch_resized(&db, &id, "10x10").unwrap(), resized);
+        assert_eq!((db.resize_cache.stats().entries, db.resize_cache.stats().hits), (1, 1));
+
+        assert!(db.replace_record(&id, &png(20, 40))?);
+        assert_eq!(db.resize_cache.stats().entries, 0);
+        assert_ne!(fetch_resized(&db, &id, "10x10").unwrap(), resized);
+
+        fs::remove_dir_all(path)
+    }
 
-    // fn evaluate_query() -> Result<(), VennbaseError> {
-    //
-    // }
+    #[test]
+    fn records_replaced_with_the_same_data_are_still_deduplicated() -> io::Result<()> {
+        let config = VennbaseConfig { dedup: DedupMode::Existing, ..VennbaseConfig::default() };
+        let (mut db, path) = temp_db_with(&config)?;
+        let mimetype = MimeType::from("text/plain").unwrap();
+        let id = db.save_record(&mimetype, b"same data", vec![])?;
+
+        assert!(db.replace_record(&id, b"same data")?);
+        assert_eq!(db.hashes.records_with_hash(&content_hash(b"same data")), [id]);
+        assert_eq!(db.save_record(&mimetype, b"same data", vec![])?, id);
+
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn replacing_a_deduplicated_record_keeps_its_duplicates() -> io::Result<()> {
+        let config = VennbaseConfig { dedup: DedupMode::Alias, ..VennbaseConfig::default() };
+        let (mut db, path) = temp_db_with(&config)?;
+        let mimetype = MimeType::from("text/plain").unwrap();
+        let original = db.save_record(&mimetype, b"shared data", vec![])?;
+        let alias = db.save_record(&mimetype, b"shared data", vec![])?;
+        let read = |db: &Vennbase, id| {
+            let (_, mut record) = db.fetch_record_by_id(id, &None).unwrap().unwrap();
+            let mut data = Vec::new();
+            record.read_to_end(&mut data).unwrap();
+            data
+        };
+
+        assert!(db.replace_record(&original, b"new data")?);
+        assert_eq!(read(&db, &original), b"new data");
+        assert_eq!(read(&db, &alias), b"shared data");
+        assert!(db.replace_record(&alias, b"other data")?);
+        assert_eq!(read(&db, &original), b"new data");
+        assert_eq!(read(&db, &alias), b"other data");
+
+        // Saves in `existing` mode share the record, so it can't be replaced until it's
+        // the last one
+        db.dedup = DedupMode::Existing;
+        let id = db.save_record(&mimetype, b"new data", vec![])?;
+        assert_eq!(id, original);
+        assert!(matches!(db.validate_replacement(&id, b"data"), Err(ReplaceError::Shared { saves: 2, .. })));
+        assert!(db.delete_record(&id)?);
+        assert!(db.validate_replacement(&id, b"data").is_ok());
+
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn encrypted_data_is_bound_to_its_record() -> io::Result<()> {
+        let key_path = testing::temp_path();
+        fs::write(&key_path, "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff")?;
+        let config = VennbaseConfig {
+            dedup: DedupMode::Alias,
+            encryption: crate::features::encryption::EncryptionConfig { key_file: Some(key_path.to_string_lossy().into()), ..Default::default() },
+            ..VennbaseConfig::default()
+        };
+        let (mut db, path) = temp_db_with(&config)?;
+        let mimetype = MimeType::from("text/plain").unwrap();
+        let original = db.save_record(&mimetype, b"shared data", vec![])?;
+        let alias = db.save_record(&mimetype, b"shared data", vec![])?;
+        let read = |db: &Vennbase, id| -> io::Result<Vec<u8>> {
+            let (_, mut record) = db.fetch_record_by_id(id, &None)?.unwrap();
+            let mut data = Vec::new();
+            record.read_to_end(&mut data)?;
+            Ok(data)
+        };
+        assert_eq!(read(&db, &alias)?, b"shared data");
+
+        // The alias is the only record left with the data, which is encrypted again for it
+        db.delete_record(&original)?;
+        db.compact()?;
+        assert_eq!(read(&db, &alias)?, b"shared data");
+
+        // The data of a record can't be read as the data of another one
+        let other = db.save_record(&mimetype, b"other data", vec![])?;
+        let partition = &db.partitions[&mimetype];
+        let record = partition.fetch_record(&other)?.map(StoredRecord::InDiskRecord).unwrap();
+        let keys = db.encryption.as_ref().unwrap();
+        assert!(keys.decrypt_record(&alias, record)?.read_to_end(&mut Vec::new()).is_err());
+
+        fs::remove_file(key_path)?;
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn variants_are_generated_when_images_are_saved() -> io::Result<()> {
+        let config = VennbaseConfig {
+            variants: HashMap::from([("thumb".to_owned(), "10x10".to_owned())]),
+            ..VennbaseConfig::default()
+        };
+        let (mut db, path) = temp_db_with(&config)?;
+        let id = db.save_record(&MimeType::from("image/png").unwrap(), &png(40, 20), vec![])?;
+        let text = db.save_record(&MimeType::from("text/plain").unwrap(), b"not an image", vec![])?;
+
+        assert!(db.variants.contains_record(&variant_id(&id, "thumb")));
+        assert!(!db.variants.contains_record(&variant_id(&text, "thumb")));
+        let (mimetype, mut variant) = db.fetch_variant(&id, "thumb")?.unwrap();
+        let mut data = Vec::new();
+        variant.read_to_end(&mut data)?;
+        assert_eq!(mimetype.as_str(), "image/png");
+        assert_eq!(image::load_from_memory(&data).unwrap().width(), 10);
+
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn undecodable_images_are_only_backfilled_once() -> io::Result<()> {
+        let (mut db, path) = temp_db()?;
+        let mimetype = MimeType::from("image/png").unwrap();
+        let broken = db.save_record(&mimetype, b"not a png", vec![])?;
+        let image = db.save_record(&mimetype, &png(8, 8), vec![])?;
+        assert!(db.metadata.is_undecodable(&broken) && !db.metadata.is_undecodable(&image));
+
+        // Like a database from before metadata was stored
+        db.metadata.records.clear();
+        db.metadata.undecodable.clear();
+        assert_eq!(db.records_missing_metadata().len(), 2);
+        db.backfill_metadata()?;
+        assert!(db.records_missing_metadata().is_empty());
+        assert!(db.metadata.get(&image).is_some_and(|metadata| metadata.dhash.is_some()));
+
+        drop(db);
+        let db = Vennbase::from_dir(path.to_str().unwrap(), &VennbaseConfig::default())?;
+        assert!(db.metadata.is_undecodable(&broken));
+        assert!(db.records_missing_metadata().is_empty());
+
+        fs::remove_dir_all(path)
+    }
 }
@@ -1,83 +1,289 @@
-use std::{collections::HashMap, hash::Hash, rc::Rc};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
-struct LRUCacheList<V> {
-    size: i32,
-    prev: Option<Box<LRUCacheList<V>>>,
-    next: Option<Box<LRUCacheList<V>>>,
-    val: Option<Rc<V>>,
+struct LRUCacheEntry<K, V> {
+    key: K,
+    val: V,
+    size: usize,
+    saved_at: Instant,
+    // Towards the most recently used entry
+    prev: Option<usize>,
+    // Towards the least recently used entry
+    next: Option<usize>,
 }
 
-impl<V> LRUCacheList<V> {
-    fn new() -> LRUCacheList<V> {
+/// Doubly linked list of entries, ordered from the most to the least recently used.
+///
+/// Nodes live in a slab and are linked by their index, so any entry can be unlinked in
+/// O(1) once the map gives us its index.
+struct LRUCacheList<K, V> {
+    slab: Vec<Option<LRUCacheEntry<K, V>>>,
+    // Indices of the slab that can be reused
+    free: Vec<usize>,
+    head: Option<usize>,
+    tail: Option<usize>,
+}
+
+impl<K, V> LRUCacheList<K, V> {
+    fn new() -> LRUCacheList<K, V> {
         LRUCacheList {
-            size: 0,
-            prev: None,
-            next: None,
-            val: None,
+            slab: Vec::new(),
+            free: Vec::new(),
+            head: None,
+            tail: None,
+        }
+    }
+
+    fn entry(&self, i: usize) -> &LRUCacheEntry<K, V> {
+        self.slab[i].as_ref().expect("linked nodes to be occupied")
+    }
+
+    fn entry_mut(&mut self, i: usize) -> &mut LRUCacheEntry<K, V> {
+        self.slab[i].as_mut().expect("linked nodes to be occupied")
+    }
+
+    /// Inserts an entry as the most recently used one and returns its index.
+    fn push_front(&mut self, mut entry: LRUCacheEntry<K, V>) -> usize {
+        entry.prev = None;
+        entry.next = self.head;
+        let i = match self.free.pop() {
+            Some(i) => {
+                self.slab[i] = Some(entry);
+                i
+            },
+            None => {
+                self.slab.push(Some(entry));
+                self.slab.len() - 1
+            },
+        };
+        match self.head {
+            Some(head) => self.entry_mut(head).prev = Some(i),
+            None => self.tail = Some(i),
+        }
+        self.head = Some(i);
+        i
+    }
+
+    fn unlink(&mut self, i: usize) -> LRUCacheEntry<K, V> {
+        let entry = self.slab[i].take().expect("linked nodes to be occupied");
+        match entry.prev {
+            Some(prev) => self.entry_mut(prev).next = entry.next,
+            None => self.head = entry.next,
+        }
+        match entry.next {
+            Some(next) => self.entry_mut(next).prev = entry.prev,
+            None => self.tail = entry.prev,
         }
+        self.free.push(i);
+        entry
+    }
+
+    fn clear(&mut self) {
+        self.slab.clear();
+        self.free.clear();
+        self.head = None;
+        self.tail = None;
+    }
+}
+
+struct LRUCacheState<K, V> {
+    map: HashMap<K, usize>,
+    list: LRUCacheList<K, V>,
+    size: usize,
+}
+
+impl<K: Eq + Hash, V> LRUCacheState<K, V> {
+    fn remove_at(&mut self, i: usize) -> LRUCacheEntry<K, V> {
+        let entry = self.list.unlink(i);
+        self.map.remove(&entry.key);
+        self.size -= entry.size;
+        entry
     }
 }
 
-/// A Least Recently Used (LRU) cache
+/// Snapshot of the usage of a cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub size: usize,
+    pub max_size: usize,
+}
+
+impl CacheStats {
+    /// Ratio of lookups that were served by the cache, from 0 to 1.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+/// A thread-safe Least Recently Used (LRU) cache.
+///
+/// Every entry has a size given by `calc_size`. When the sum of the sizes exceeds
+/// `max_size`, the least recently used entries are evicted. Entries older than `ttl`
+/// are treated as missing.
 pub struct LRUCache<K, V> {
-    ttl: u32,
-    max_size: u32,
-    records_map: HashMap<K, V>,
-    records_list: LRUCacheList<V>,
-    calc_size: fn(val: &V) -> i32
+    ttl: Option<Duration>,
+    max_size: usize,
+    state: Mutex<LRUCacheState<K, V>>,
+    calc_size: fn(val: &V) -> usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
 }
 
-impl<K: Eq + Hash, V> LRUCache<K, V> {
-    pub fn get(&self, key: K) -> Option<&V> {
-        match self.records_map.get(&key) {
-            Some(record) => Some(record),
-            None => todo!(),
+impl<K: Eq + Hash + Clone, V: Clone> LRUCache<K, V> {
+    /// Returns a copy of the cached value, marking it as the most recently used.
+    ///
+    /// Values are cloned because they can't outlive the lock, so prefer cheap to clone
+    /// values like `Arc`s.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut state = self.state.lock().unwrap();
+        let found = match state.map.get(key).copied() {
+            Some(i) if self.is_expired(state.list.entry(i)) => {
+                state.remove_at(i);
+                None
+            },
+            Some(i) => {
+                // Move it to the front of the list
+                let entry = state.list.unlink(i);
+                let val = entry.val.clone();
+                let i = state.list.push_front(entry);
+                state.map.insert(key.clone(), i);
+                Some(val)
+            },
+            None => None,
+        };
+        let counter = if found.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    pub fn save(&self, key: K, val: V) {
+        let item_size = (self.calc_size)(&val);
+        let mut state = self.state.lock().unwrap();
+        if let Some(i) = state.map.get(&key).copied() {
+            state.remove_at(i);
+        }
+        // Entries bigger than the whole cache would evict everything for nothing
+        if item_size > self.max_size {
+            return;
         }
+        while state.size + item_size > self.max_size {
+            match state.list.tail {
+                Some(oldest) => state.remove_at(oldest),
+                None => break,
+            };
+        }
+        let i = state.list.push_front(LRUCacheEntry {
+            key: key.clone(),
+            val,
+            size: item_size,
+            saved_at: Instant::now(),
+            prev: None,
+            next: None,
+        });
+        state.map.insert(key, i);
+        state.size += item_size;
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut state = self.state.lock().unwrap();
+        let i = state.map.get(key).copied()?;
+        Some(state.remove_at(i).val)
     }
 
-    pub fn save(&mut self, _key: K, val: V) {
-        let _item_size = (self.calc_size)(&val);
-        let _val_ptr = Rc::new(val);
+    /// Removes all the entries for which `keep` returns false.
+    pub fn retain<F>(&self, mut keep: F)
+    where F: FnMut(&K, &V) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let removed = state.map
+            .values()
+            .copied()
+            .filter(|i| {
+                let entry = state.list.entry(*i);
+                !keep(&entry.key, &entry.val)
+            })
+            .collect::<Vec<_>>();
+        for i in removed {
+            state.remove_at(i);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.map.clear();
+        state.list.clear();
+        state.size = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: state.map.len(),
+            size: state.size,
+            max_size: self.max_size,
+        }
+    }
+
+    fn is_expired(&self, entry: &LRUCacheEntry<K, V>) -> bool {
+        self.ttl.is_some_and(|ttl| entry.saved_at.elapsed() > ttl)
     }
 }
 
 pub struct LRUCacheBuilder<V> {
-    ttl: u32,
-    max_size: u32,
-    calc_size: fn(val: &V) -> i32
+    ttl: Option<Duration>,
+    max_size: usize,
+    calc_size: fn(val: &V) -> usize
 }
 
 impl<V> LRUCacheBuilder<V> {
     pub fn new() -> Self {
         LRUCacheBuilder::<V> {
-            ttl: 0,
+            ttl: None,
             max_size: 0,
             calc_size: |_| 1
         }
     }
 
-    pub fn with_ttl(mut self, ttl: u32) -> Self {
-        self.ttl = ttl;
+    /// Entries older than `ttl` are evicted when they are looked up. By default,
+    /// entries never expire.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
         self
     }
 
-    pub fn with_max_size(mut self, max_size: u32) -> Self {
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
         self.max_size = max_size;
         self
     }
 
-    pub fn with_calc_size(mut self, calc_size: fn(val: &V) -> i32) -> Self {
+    /// By default, every entry has a size of 1, so `max_size` is the number of entries.
+    pub fn with_calc_size(mut self, calc_size: fn(val: &V) -> usize) -> Self {
         self.calc_size = calc_size;
         self
     }
 
-    pub fn build(self) -> LRUCache<String, V> {
-        LRUCache::<String, V> {
+    pub fn build<K>(self) -> LRUCache<K, V> {
+        LRUCache::<K, V> {
             ttl: self.ttl,
             max_size: self.max_size,
             calc_size: self.calc_size,
-            records_list: LRUCacheList::new(),
-            records_map: HashMap::new()
+            state: Mutex::new(LRUCacheState {
+                map: HashMap::new(),
+                list: LRUCacheList::new(),
+                size: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
         }
     }
 }
@@ -87,3 +293,65 @@ impl<V> Default for LRUCacheBuilder<V> {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let cache = LRUCacheBuilder::<u32>::new().with_max_size(2).build();
+        cache.save("a", 1);
+        cache.save("b", 2);
+        assert_eq!(cache.get(&"a"), Some(1));
+        cache.save("c", 3);
+
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+    }
+
+    #[test]
+    fn entries_are_weighted_by_their_size() {
+        let cache = LRUCacheBuilder::<Vec<u8>>::new()
+            .with_max_size(10)
+            .with_calc_size(Vec::len)
+            .build();
+        cache.save(1, vec![0; 4]);
+        cache.save(2, vec![0; 4]);
+        cache.save(3, vec![0; 4]);
+        // Too big to be cached at all
+        cache.save(4, vec![0; 11]);
+
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.stats().size, 8);
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn expired_entries_are_missing() {
+        let cache = LRUCacheBuilder::<u32>::new()
+            .with_max_size(2)
+            .with_ttl(Duration::ZERO)
+            .build();
+        cache.save("a", 1);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let cache = LRUCacheBuilder::<u32>::new().with_max_size(2).build();
+        cache.save("a", 1);
+        cache.get(&"a");
+        cache.get(&"a");
+        cache.get(&"b");
+        cache.retain(|_, val| *val != 1);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 0));
+        assert!((stats.hit_rate() - 2.0 / 3.0).abs() < f64::EPSILON);
+    }
+}
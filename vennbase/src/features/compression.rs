@@ -0,0 +1,172 @@
+use std::io::{self, prelude::*, SeekFrom};
+use flate2::Compression;
+use flate2::read::{GzDecoder, GzEncoder};
+
+use crate::db::partition::StoredRecord;
+use crate::db::types::MimeType;
+use crate::features::encryption::DecryptedRecord;
+use crate::features::resize::is_resizable_format;
+
+/// Compressed records are a gzip stream followed by the length of the original data, so
+/// their size is known without decompressing them.
+pub const TRAILER_SIZE_BYTES: u64 = 8;
+
+/// Whether a mimetype matches any of the patterns of `VennbaseConfig::compression`,
+/// like `application/json` or `text/*`.
+///
+/// Images that can be resized are never compressed, since they are compressed already
+/// and they are decoded anyway when they are resized.
+pub fn should_compress(patterns: &[String], mimetype: &MimeType) -> bool {
+    if is_resizable_format(mimetype) {
+        return false;
+    }
+    patterns.iter().any(|pattern| mimetype.matches(pattern))
+}
+
+/// Compresses the data of a record, unless it doesn't get any smaller.
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut compressed = Vec::new();
+    GzEncoder::new(data, Compression::default())
+        .read_to_end(&mut compressed)
+        .expect("reading from memory not to fail");
+    compressed.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Compresses the data as it's read, like `compress`. Since the data isn't available
+/// up front, it's compressed even if it doesn't get any smaller.
+pub struct CompressingReader<R> {
+    encoder: GzEncoder<CountingReader<R>>,
+    trailer: Option<io::Cursor<[u8; TRAILER_SIZE_BYTES as usize]>>,
+}
+
+impl<R: Read> CompressingReader<R> {
+    pub fn new(inner: R) -> Self {
+        CompressingReader {
+            encoder: GzEncoder::new(CountingReader { inner, count: 0 }, Compression::default()),
+            trailer: None,
+        }
+    }
+}
+
+impl<R: Read> Read for CompressingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.trailer.is_none() {
+            let read = self.encoder.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            let length = self.encoder.get_ref().count;
+            self.trailer = Some(io::Cursor::new(length.to_le_bytes()));
+        }
+        self.trailer.as_mut().unwrap().read(buf)
+    }
+}
+
+/// Compressed data, either in memory or in a partition file.
+#[derive(Debug)]
+pub enum CompressedData {
+    InDisk(io::Take<io::BufReader<std::fs::File>>),
+    InMemory(io::Cursor<Vec<u8>>),
+    Decrypted(Box<DecryptedRecord>),
+}
+
+impl Read for CompressedData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            CompressedData::InDisk(reader) => reader.read(buf),
+            CompressedData::InMemory(reader) => reader.read(buf),
+            CompressedData::Decrypted(record) => record.read(buf),
+        }
+    }
+}
+
+/// The original data of a compressed record, decompressed as it's read.
+#[derive(Debug)]
+pub struct DecompressedRecord {
+    decoder: io::Take<GzDecoder<CompressedData>>,
+}
+
+impl DecompressedRecord {
+    /// Decompresses a record as it's stored in its partition.
+    pub fn new(record: StoredRecord) -> io::Result<Self> {
+        let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "Truncated compressed record");
+        let (data, trailer) = match record {
+            StoredRecord::InDiskRecord(mut reader) => {
+                let size = reader.limit().checked_sub(TRAILER_SIZE_BYTES).ok_or_else(corrupt)?;
+                let mut trailer = [0; TRAILER_SIZE_BYTES as usize];
+                reader.get_mut().seek(SeekFrom::Current(size as i64))?;
+                reader.get_mut().read_exact(&mut trailer)?;
+                reader.get_mut().seek(SeekFrom::Current(-((size + TRAILER_SIZE_BYTES) as i64)))?;
+                reader.set_limit(size);
+                (CompressedData::InDisk(reader), trailer)
+            },
+            StoredRecord::InMemoryRecord(mut data) => {
+                let size = data.len().checked_sub(TRAILER_SIZE_BYTES as usize).ok_or_else(corrupt)?;
+                let trailer = data.split_off(size).try_into().expect("to be as long as the trailer");
+                (CompressedData::InMemory(io::Cursor::new(data)), trailer)
+            },
+            StoredRecord::Decrypted(mut record) => {
+                let trailer = record.split_off_tail(TRAILER_SIZE_BYTES)?;
+                let trailer = trailer.try_into().expect("to be as long as the trailer");
+                (CompressedData::Decrypted(Box::new(record)), trailer)
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported compressed record")),
+        };
+        let size = u64::from_le_bytes(trailer);
+        Ok(DecompressedRecord { decoder: GzDecoder::new(data).take(size) })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.decoder.limit()
+    }
+
+    /// Narrows the record to the bytes from `start` to `end`, both included. The bytes
+    /// before `start` still have to be decompressed.
+    pub fn into_range(mut self, start: u64, end: u64) -> io::Result<Self> {
+        io::copy(&mut self.decoder.by_ref().take(start), &mut io::sink())?;
+        self.decoder.set_limit(end - start + 1);
+        Ok(self)
+    }
+}
+
+impl Read for DecompressedRecord {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_and_buffered_compression_round_trip() -> io::Result<()> {
+        let text = "vennbase ".repeat(1000);
+        let compressed = compress(text.as_bytes()).unwrap();
+        let mut streamed = Vec::new();
+        CompressingReader::new(text.as_bytes()).read_to_end(&mut streamed)?;
+        assert_eq!(compressed, streamed);
+        assert!(compress(b"tiny").is_none());
+
+        let record = DecompressedRecord::new(StoredRecord::InMemoryRecord(compressed))?;
+        assert_eq!(record.size(), 9000);
+        let mut range = String::new();
+        record.into_range(4500, 4507)?.read_to_string(&mut range)?;
+        assert_eq!(range, "vennbase");
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::features::encryption::SideFile;
+use crate::features::sniffing::SniffError;
+
+/// What `save` does with data that is already stored in the same partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupMode {
+    /// Every record gets its own copy of the data
+    #[default]
+    Off,
+    /// The existing record is returned, and the new tags are added to it
+    Existing,
+    /// A new record is created, sharing the data of the existing one
+    Alias,
+}
+
+/// SHA-256 of the data, in hex.
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Hashes the data as it's read, for records that are never held in memory.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: Sha256::new() }
+    }
+
+    /// The hash of the data read so far, like `content_hash`.
+    pub fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Why the data of a record can't be replaced.
+#[derive(Debug)]
+pub enum ReplaceError {
+    /// The record was returned by several saves in `existing` mode, so replacing it would
+    /// replace the data of every one of them
+    Shared { id: uuid::Uuid, saves: u32 },
+    Mime(SniffError),
+}
+
+impl std::fmt::Display for ReplaceError {
+    /// Formats the error as `<kind> <offset> <message>`, like query errors.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplaceError::Shared { id, saves } => {
+                write!(f, "shared-record 0 record '{id}' was returned by {saves} saves of the same data")
+            },
+            ReplaceError::Mime(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<SniffError> for ReplaceError {
+    fn from(err: SniffError) -> Self {
+        ReplaceError::Mime(err)
+    }
+}
+
+/// Content hashes of the records of the database, by record id.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContentHashMap {
+    #[serde(skip)]
+    pub file: SideFile,
+    pub records: HashMap<uuid::Uuid, String>,
+    /// How many times `save` returned a record in `existing` mode, besides its first save.
+    /// Such records are only deleted when all the references are deleted
+    #[serde(default)]
+    pub references: HashMap<uuid::Uuid, u32>,
+    // Inverse of `records`
+    #[serde(skip)]
+    by_hash: HashMap<String, Vec<uuid::Uuid>>,
+}
+
+impl ContentHashMap {
+    /// Loads the hashes from `file`. Databases created before content hashing existed
+    /// don't have the file, so an empty map is returned in that case.
+    pub fn from_file(file: SideFile) -> io::Result<Self> {
+        if !file.exists() {
+            return Ok(ContentHashMap {
+                file,
+                records: HashMap::new(),
+                references: HashMap::new(),
+                by_hash: HashMap::new(),
+            });
+        }
+        let mut hashes = file.read_json::<ContentHashMap>()?;
+        hashes.file = file;
+        for (id, hash) in &hashes.records {
+            hashes.by_hash.entry(hash.clone()).or_default().push(*id);
+        }
+        Ok(hashes)
+    }
+
+    fn flush_data(&self) -> io::Result<()> {
+        self.file.write_json(self)
+    }
+
+    pub fn get(&self, record_id: &uuid::Uuid) -> Option<&String> {
+        self.records.get(record_id)
+    }
+
+    /// Records whose data has the given hash.
+    pub fn records_with_hash(&self, hash: &str) -> &[uuid::Uuid] {
+        self.by_hash.get(hash).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn insert(&mut self, record_id: uuid::Uuid, hash: String) -> io::Result<()> {
+        // The old hash goes first, it may be the same as the new one
+        if let Some(old_hash) = self.records.insert(record_id, hash.clone()) {
+            self.remove_from_index(&record_id, &old_hash);
+        }
+        self.by_hash.entry(hash).or_default().push(record_id);
+        self.flush_data()
+    }
+
+    pub fn remove(&mut self, record_id: &uuid::Uuid) -> io::Result<()> {
+        self.references.remove(record_id);
+        if let Some(hash) = self.records.remove(record_id) {
+            self.remove_from_index(record_id, &hash);
+            self.flush_data()?;
+        }
+        Ok(())
+    }
+
+    fn remove_from_index(&mut self, record_id: &uuid::Uuid, hash: &str) {
+        if let Some(ids) = self.by_hash.get_mut(hash) {
+            ids.retain(|id| id != record_id);
+            if ids.is_empty() {
+                self.by_hash.remove(hash);
+            }
+        }
+    }
+
+    /// How many deduplicated saves returned a record, besides its first save.
+    pub fn references(&self, record_id: &uuid::Uuid) -> u32 {
+        self.references.get(record_id).copied().unwrap_or(0)
+    }
+
+    /// Counts another reference to a record returned by a deduplicated `save`.
+    pub fn add_reference(&mut self, record_id: &uuid::Uuid) -> io::Result<()> {
+        *self.references.entry(*record_id).or_default() += 1;
+        self.flush_data()
+    }
+
+    /// Drops a reference to a record, returning false if it was the last one, so the
+    /// record must be deleted.
+    pub fn release(&mut self, record_id: &uuid::Uuid) -> io::Result<bool> {
+        match self.references.get_mut(record_id) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    self.references.remove(record_id);
+                }
+                self.flush_data()?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
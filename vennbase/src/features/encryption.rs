@@ -0,0 +1,461 @@
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, AeadCore, Nonce};
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+
+use crate::db::partition::StoredRecord;
+use crate::db::types::MimeType;
+
+/// Encrypted data is split in segments of this size, sealed one by one, so records can be
+/// encrypted while they are received and ranges can be decrypted without the whole record.
+const SEGMENT_SIZE: u64 = 64 * 1024;
+const KEY_ID_SIZE: usize = 8;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: u64 = 16;
+// Encrypted data starts with the id of its key and its nonce
+const HEADER_SIZE: u64 = (KEY_ID_SIZE + NONCE_SIZE) as u64;
+/// Encrypted side files start with this, to tell them apart from plain JSON
+const SIDE_FILE_MAGIC: &[u8] = b"venncrypt";
+
+type KeyId = [u8; KEY_ID_SIZE];
+
+/// Settings of the encryption at rest. Records are stored in clear unless a key is given.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// File with the key used to encrypt new data, as 64 hex digits
+    pub key_file: Option<String>,
+    /// Files with older keys, so the data encrypted with them can be read until the next
+    /// compaction re-encrypts it with `key_file`
+    pub previous_key_files: Vec<String>,
+    /// Mimetypes of the records that are encrypted, like `application/pdf` or `image/*`
+    pub mimetypes: Vec<String>,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        EncryptionConfig {
+            key_file: None,
+            previous_key_files: Vec::new(),
+            mimetypes: vec!["*".to_owned()],
+        }
+    }
+}
+
+/// The keys of an encrypted database.
+pub struct EncryptionKeys {
+    current: (KeyId, ChaCha20Poly1305),
+    previous: Vec<(KeyId, ChaCha20Poly1305)>,
+    mimetypes: Vec<String>,
+}
+
+impl std::fmt::Debug for EncryptionKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the keys themselves
+        f.debug_struct("EncryptionKeys").field("mimetypes", &self.mimetypes).finish_non_exhaustive()
+    }
+}
+
+fn read_key_file(path: &str) -> io::Result<(KeyId, ChaCha20Poly1305)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{path:?} must hold a key of 64 hex digits"));
+    let hex = fs::read_to_string(path)?;
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let key = (0..32)
+        .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    let mut id = [0; KEY_ID_SIZE];
+    id.copy_from_slice(&Sha256::digest(&key)[..KEY_ID_SIZE]);
+    Ok((id, ChaCha20Poly1305::new_from_slice(&key).expect("keys to be 32 bytes long")))
+}
+
+/// The nonce of a segment, derived from the nonce of the record so segments can't be
+/// reordered.
+fn segment_nonce(nonce: &[u8; NONCE_SIZE], segment: u64) -> Nonce {
+    let mut nonce = *nonce;
+    for (byte, counter) in nonce[NONCE_SIZE - 8..].iter_mut().zip(segment.to_be_bytes()) {
+        *byte ^= counter;
+    }
+    nonce.into()
+}
+
+/// The associated data of a segment, so segments can't be moved to another record or
+/// position, nor the data be truncated at the end of a segment.
+fn segment_aad(record_id: &uuid::Uuid, segment: u64, is_last: bool) -> Vec<u8> {
+    let mut aad = record_id.as_bytes().to_vec();
+    aad.extend_from_slice(&segment.to_be_bytes());
+    aad.push(is_last as u8);
+    aad
+}
+
+/// The id side files are encrypted for, from their name, so they can't be swapped.
+fn side_file_id(path: &Path) -> uuid::Uuid {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    uuid::Uuid::new_v5(&uuid::Uuid::nil(), name.as_bytes())
+}
+
+fn decryption_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Encrypted data was tampered with or the key is wrong")
+}
+
+/// Length of the original data of an encrypted record, from its length in the partition.
+pub fn plaintext_size(encrypted_size: u64) -> Option<u64> {
+    let body = encrypted_size.checked_sub(HEADER_SIZE)?;
+    let (segments, rest) = (body / (SEGMENT_SIZE + TAG_SIZE), body % (SEGMENT_SIZE + TAG_SIZE));
+    match rest {
+        0 if segments > 0 => Some(segments * SEGMENT_SIZE),
+        rest if rest >= TAG_SIZE => Some(segments * SEGMENT_SIZE + rest - TAG_SIZE),
+        _ => None,
+    }
+}
+
+impl EncryptionKeys {
+    /// Reads the configured keys. Returns `None` if encryption is not enabled.
+    pub fn load(config: &EncryptionConfig) -> io::Result<Option<Arc<Self>>> {
+        let Some(key_file) = &config.key_file else {
+            return Ok(None);
+        };
+        Ok(Some(Arc::new(EncryptionKeys {
+            current: read_key_file(key_file)?,
+            previous: config.previous_key_files
+                .iter()
+                .map(|path| read_key_file(path))
+                .collect::<io::Result<_>>()?,
+            mimetypes: config.mimetypes.clone(),
+        })))
+    }
+
+    /// Whether new records of a mimetype are encrypted, see `EncryptionConfig::mimetypes`.
+    pub fn should_encrypt(&self, mimetype: &MimeType) -> bool {
+        self.mimetypes.iter().any(|pattern| mimetype.matches(pattern))
+    }
+
+    fn cipher(&self, id: &KeyId) -> io::Result<&ChaCha20Poly1305> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, cipher)| cipher)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Data encrypted with an unknown key"))
+    }
+
+    /// Whether a record is encrypted with the key new data is encrypted with. Records
+    /// encrypted with older keys are re-encrypted when the database is compacted.
+    pub fn is_current_key(&self, record: &DecryptedRecord) -> bool {
+        record.key_id == self.current.0
+    }
+
+    /// Encrypts the data of a record as it's read with the current key. The data can
+    /// only be decrypted as the data of that record.
+    pub fn encrypting_reader<R: Read>(&self, record_id: &uuid::Uuid, inner: R) -> EncryptingReader<R> {
+        let nonce: [u8; NONCE_SIZE] = ChaCha20Poly1305::generate_nonce(&mut OsRng).into();
+        let mut header = self.current.0.to_vec();
+        header.extend_from_slice(&nonce);
+        EncryptingReader {
+            inner,
+            cipher: self.current.1.clone(),
+            record_id: *record_id,
+            nonce,
+            segment: 0,
+            plaintext: Vec::new(),
+            output: io::Cursor::new(header),
+            done: false,
+        }
+    }
+
+    pub fn encrypt(&self, record_id: &uuid::Uuid, data: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::with_capacity(data.len() + (HEADER_SIZE + TAG_SIZE) as usize);
+        self.encrypting_reader(record_id, data)
+            .read_to_end(&mut encrypted)
+            .expect("reading from memory not to fail");
+        encrypted
+    }
+
+    /// Decrypts a record as it's stored in its partition. `record_id` is the record the
+    /// data was encrypted for, see `Partition::data_owner`.
+    pub fn decrypt_record(&self, record_id: &uuid::Uuid, record: StoredRecord) -> io::Result<DecryptedRecord> {
+        let mut data = match record {
+            StoredRecord::InDiskRecord(reader) => {
+                let size = reader.limit();
+                let mut reader = reader.into_inner();
+                let start = reader.stream_position()?;
+                EncryptedData::InDisk { reader, start, size }
+            },
+            StoredRecord::InMemoryRecord(data) => EncryptedData::InMemory(data),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported encrypted record")),
+        };
+        let size = plaintext_size(data.size()).ok_or_else(decryption_error)?;
+        let header = data.read_at(0, HEADER_SIZE)?;
+        let (key_id, nonce) = header.split_at(KEY_ID_SIZE);
+        let key_id: KeyId = key_id.try_into().unwrap();
+        Ok(DecryptedRecord {
+            data,
+            cipher: self.cipher(&key_id)?.clone(),
+            key_id,
+            record_id: *record_id,
+            nonce: nonce.try_into().unwrap(),
+            size,
+            position: 0,
+            end: size,
+            segment: None,
+        })
+    }
+
+    /// Writes a side file of the database (`.map`, `.views`...) encrypted.
+    fn encrypt_side_file(&self, path: &Path, data: &[u8]) -> Vec<u8> {
+        let mut encrypted = SIDE_FILE_MAGIC.to_vec();
+        encrypted.extend(self.encrypt(&side_file_id(path), data));
+        encrypted
+    }
+}
+
+/// Encrypts data as it's read, see `EncryptionKeys::encrypting_reader`.
+pub struct EncryptingReader<R> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    record_id: uuid::Uuid,
+    nonce: [u8; NONCE_SIZE],
+    segment: u64,
+    // Data read from `inner` and not encrypted yet
+    plaintext: Vec<u8>,
+    output: io::Cursor<Vec<u8>>,
+    done: bool,
+}
+
+impl<R: Read> Read for EncryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.output.read(buf)?;
+            if read > 0 || buf.is_empty() || self.done {
+                return Ok(read);
+            }
+            // One byte more than a segment tells whether this segment is the last one
+            let missing = SEGMENT_SIZE + 1 - self.plaintext.len() as u64;
+            (&mut self.inner).take(missing).read_to_end(&mut self.plaintext)?;
+            let is_last = self.plaintext.len() as u64 <= SEGMENT_SIZE;
+            let next = match is_last {
+                true => Vec::new(),
+                false => self.plaintext.split_off(SEGMENT_SIZE as usize),
+            };
+            let aad = segment_aad(&self.record_id, self.segment, is_last);
+            let payload = Payload { msg: &self.plaintext, aad: &aad };
+            let sealed = self.cipher
+                .encrypt(&segment_nonce(&self.nonce, self.segment), payload)
+                .map_err(|_| io::Error::other("Couldn't encrypt the data"))?;
+            self.output = io::Cursor::new(sealed);
+            self.plaintext = next;
+            self.segment += 1;
+            self.done = is_last;
+        }
+    }
+}
+
+/// Encrypted data, either in memory or in a partition file.
+#[derive(Debug)]
+enum EncryptedData {
+    InDisk { reader: BufReader<File>, start: u64, size: u64 },
+    InMemory(Vec<u8>),
+}
+
+impl EncryptedData {
+    fn size(&self) -> u64 {
+        match self {
+            EncryptedData::InDisk { size, .. } => *size,
+            EncryptedData::InMemory(data) => data.len() as u64,
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        match self {
+            EncryptedData::InDisk { reader, start, .. } => {
+                let mut data = vec![0; len as usize];
+                reader.seek(SeekFrom::Start(*start + offset))?;
+                reader.read_exact(&mut data)?;
+                Ok(data)
+            },
+            EncryptedData::InMemory(data) => Ok(data[offset as usize..(offset + len) as usize].to_vec()),
+        }
+    }
+}
+
+/// The original data of an encrypted record, decrypted as it's read.
+pub struct DecryptedRecord {
+    data: EncryptedData,
+    cipher: ChaCha20Poly1305,
+    key_id: KeyId,
+    record_id: uuid::Uuid,
+    nonce: [u8; NONCE_SIZE],
+    size: u64,
+    // Offsets of the original data that are left to read
+    position: u64,
+    end: u64,
+    // The last decrypted segment
+    segment: Option<(u64, Vec<u8>)>,
+}
+
+impl std::fmt::Debug for DecryptedRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecryptedRecord")
+            .field("data", &self.data)
+            .field("position", &self.position)
+            .field("end", &self.end)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DecryptedRecord {
+    pub fn size(&self) -> u64 {
+        self.end - self.position
+    }
+
+    /// Narrows the record to the bytes from `start` to `end`, both included. Only the
+    /// segments with those bytes are decrypted.
+    pub fn into_range(mut self, start: u64, end: u64) -> io::Result<Self> {
+        self.position += start;
+        self.end = self.position + (end - start + 1);
+        Ok(self)
+    }
+
+    /// Reads the last `len` bytes of the record and leaves them out of it, like the
+    /// trailer of compressed records.
+    pub fn split_off_tail(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        let (position, end) = (self.position, self.end);
+        self.position = end.checked_sub(len)
+            .filter(|start| *start >= position)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Truncated encrypted record"))?;
+        let mut tail = vec![0; len as usize];
+        self.read_exact(&mut tail)?;
+        (self.position, self.end) = (position, end - len);
+        Ok(tail)
+    }
+
+    fn decrypt_segment(&mut self, segment: u64) -> io::Result<Vec<u8>> {
+        let offset = HEADER_SIZE + segment * (SEGMENT_SIZE + TAG_SIZE);
+        let len = (self.data.size() - offset).min(SEGMENT_SIZE + TAG_SIZE);
+        let sealed = self.data.read_at(offset, len)?;
+        let is_last = segment == self.size.saturating_sub(1) / SEGMENT_SIZE;
+        let aad = segment_aad(&self.record_id, segment, is_last);
+        let payload = Payload { msg: &sealed, aad: &aad };
+        self.cipher
+            .decrypt(&segment_nonce(&self.nonce, segment), payload)
+            .map_err(|_| decryption_error())
+    }
+}
+
+impl Read for DecryptedRecord {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.end || buf.is_empty() {
+            return Ok(0);
+        }
+        let segment = self.position / SEGMENT_SIZE;
+        if self.segment.as_ref().is_none_or(|(index, _)| *index != segment) {
+            self.segment = Some((segment, self.decrypt_segment(segment)?));
+        }
+        let (_, data) = self.segment.as_ref().unwrap();
+        let from = (self.position % SEGMENT_SIZE) as usize;
+        let len = buf.len().min(data.len() - from).min((self.end - self.position) as usize);
+        buf[..len].copy_from_slice(&data[from..from + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+/// A side file of the database, like `.map` or `.views`, stored as JSON.
+///
+/// Side files may hold tags and other private data, so they are encrypted along with the
+/// records. Plain side files are still read, and they are encrypted when they are written.
+#[derive(Debug, Clone, Default)]
+pub struct SideFile {
+    pub path: PathBuf,
+    keys: Option<Arc<EncryptionKeys>>,
+}
+
+impl SideFile {
+    pub fn new(path: PathBuf, keys: Option<Arc<EncryptionKeys>>) -> Self {
+        SideFile { path, keys }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    pub fn read_json<T: DeserializeOwned>(&self) -> io::Result<T> {
+        let data = fs::read(&self.path)?;
+        let Some(encrypted) = data.strip_prefix(SIDE_FILE_MAGIC) else {
+            return Ok(serde_json::from_slice(&data)?);
+        };
+        let keys = self.keys.as_ref().ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} is encrypted, but no key is configured", self.path)
+        ))?;
+        let mut json = Vec::new();
+        keys.decrypt_record(&side_file_id(&self.path), StoredRecord::InMemoryRecord(encrypted.to_vec()))?.read_to_end(&mut json)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    /// Writes the file again with the current key, if it exists. Used to encrypt the side
+    /// files written before encryption was enabled, and to rotate their key.
+    pub fn rewrite(&self) -> io::Result<()> {
+        if !self.exists() {
+            return Ok(());
+        }
+        self.write_json(&self.read_json::<serde_json::Value>()?)
+    }
+
+    pub fn write_json<T: Serialize>(&self, value: &T) -> io::Result<()> {
+        let json = serde_json::to_vec(value).unwrap();
+        match &self.keys {
+            Some(keys) => fs::write(&self.path, keys.encrypt_side_file(&self.path, &json)),
+            None => fs::write(&self.path, json),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::vennbase::testing::temp_path;
+
+    #[test]
+    fn segments_are_decrypted_and_tampering_is_detected() -> io::Result<()> {
+        let key_path = temp_path();
+        fs::write(&key_path, "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\n")?;
+        let config = EncryptionConfig { key_file: Some(key_path.to_string_lossy().into()), ..Default::default() };
+        let keys = EncryptionKeys::load(&config)?.unwrap();
+        fs::remove_file(key_path)?;
+
+        let data = (0..3 * SEGMENT_SIZE as usize).map(|i| i as u8).collect::<Vec<_>>();
+        let id = uuid::Uuid::new_v4();
+        let encrypted = keys.encrypt(&id, &data);
+        assert_eq!(plaintext_size(encrypted.len() as u64), Some(data.len() as u64));
+        assert_eq!(plaintext_size(keys.encrypt(&id, b"").len() as u64), Some(0));
+
+        let record = keys.decrypt_record(&id, StoredRecord::InMemoryRecord(encrypted.clone()))?;
+        let mut range = Vec::new();
+        record.into_range(SEGMENT_SIZE - 2, SEGMENT_SIZE + 1)?.read_to_end(&mut range)?;
+        assert_eq!(range, &data[SEGMENT_SIZE as usize - 2..SEGMENT_SIZE as usize + 2]);
+
+        // Dropping the last segment is noticed, not just flipping bits
+        let truncated = encrypted[..encrypted.len() - (SEGMENT_SIZE + TAG_SIZE) as usize].to_vec();
+        let mut record = keys.decrypt_record(&id, StoredRecord::InMemoryRecord(truncated))?;
+        assert!(record.read_to_end(&mut Vec::new()).is_err());
+
+        // So is moving the data to another record, or swapping two segments
+        let mut record = keys.decrypt_record(&uuid::Uuid::new_v4(), StoredRecord::InMemoryRecord(encrypted.clone()))?;
+        assert!(record.read_to_end(&mut Vec::new()).is_err());
+        let (first, second) = (HEADER_SIZE as usize, (HEADER_SIZE + SEGMENT_SIZE + TAG_SIZE) as usize);
+        let mut swapped = encrypted.clone();
+        swapped[first..second].copy_from_slice(&encrypted[second..2 * second - first]);
+        swapped[second..2 * second - first].copy_from_slice(&encrypted[first..second]);
+        let mut record = keys.decrypt_record(&id, StoredRecord::InMemoryRecord(swapped))?;
+        assert!(record.read_to_end(&mut Vec::new()).is_err());
+        Ok(())
+    }
+}
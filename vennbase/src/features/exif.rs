@@ -0,0 +1,178 @@
+use image::DynamicImage;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// Chunks that may hold camera, location or authoring information
+const PNG_METADATA_CHUNKS: [&[u8]; 4] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt"];
+
+/// A JPEG segment, given by its marker and its payload (without the length bytes)
+struct JpegSegment<'a> {
+    marker: u8,
+    payload: &'a [u8],
+    // The whole segment, marker included
+    raw: &'a [u8],
+}
+
+/// Splits a JPEG file into the segments that come before the image data, and the image
+/// data itself.
+///
+/// Returns `None` if the file is malformed.
+fn jpeg_segments(data: &[u8]) -> Option<(Vec<JpegSegment<'_>>, &[u8])> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        match marker {
+            // Fill bytes
+            0xFF => pos += 1,
+            // Markers without a payload
+            0x01 | 0xD0..=0xD7 => {
+                segments.push(JpegSegment { marker, payload: &[], raw: &data[pos..pos + 2] });
+                pos += 2;
+            },
+            _ => {
+                let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+                let end = pos + 2 + len;
+                if len < 2 || end > data.len() {
+                    return None;
+                }
+                segments.push(JpegSegment { marker, payload: &data[pos + 4..end], raw: &data[pos..end] });
+                pos = end;
+                // The entropy-coded image data follows the start of scan
+                if marker == 0xDA {
+                    return Some((segments, &data[pos..]));
+                }
+            },
+        }
+    }
+}
+
+fn is_metadata_segment(segment: &JpegSegment<'_>) -> bool {
+    segment.marker == 0xE1
+        && (segment.payload.starts_with(EXIF_HEADER) || segment.payload.starts_with(XMP_HEADER))
+}
+
+/// Reads the orientation tag (from 1 to 8) of the EXIF metadata of a JPEG file.
+pub fn jpeg_orientation(data: &[u8]) -> Option<u16> {
+    let (segments, _) = jpeg_segments(data)?;
+    let tiff = segments
+        .iter()
+        .find(|segment| segment.marker == 0xE1 && segment.payload.starts_with(EXIF_HEADER))?
+        .payload
+        .get(EXIF_HEADER.len()..)?;
+
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let read_u16 = |at: usize| -> Option<u16> {
+        let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    };
+    let read_u32 = |at: usize| -> Option<u32> {
+        let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?, *tiff.get(at + 2)?, *tiff.get(at + 3)?];
+        Some(if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    let entries = read_u16(ifd)? as usize;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|entry| read_u16(*entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| read_u16(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Rotates and flips a decoded image so it's displayed upright without its orientation tag.
+pub fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Removes the EXIF and XMP segments of a JPEG file, without re-encoding it.
+///
+/// Returns `None` if the file is malformed.
+pub fn strip_jpeg_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let (segments, image_data) = jpeg_segments(data)?;
+    let mut stripped = Vec::with_capacity(data.len());
+    stripped.extend_from_slice(&data[0..2]);
+    for segment in segments.iter().filter(|segment| !is_metadata_segment(segment)) {
+        stripped.extend_from_slice(segment.raw);
+    }
+    stripped.extend_from_slice(image_data);
+    Some(stripped)
+}
+
+/// Removes the EXIF and textual chunks of a PNG file, without re-encoding it.
+///
+/// Returns `None` if the file is malformed.
+pub fn strip_png_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    let mut stripped = Vec::with_capacity(data.len());
+    stripped.extend_from_slice(PNG_SIGNATURE);
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let chunk_type = data.get(pos + 4..pos + 8)?;
+        // Length, type, data and CRC
+        let end = pos + 12 + len;
+        let chunk = data.get(pos..end)?;
+        if !PNG_METADATA_CHUNKS.contains(&chunk_type) {
+            stripped.extend_from_slice(chunk);
+        }
+        pos = end;
+    }
+    Some(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A JPEG header with an EXIF segment holding only the orientation tag
+    fn jpeg_with_orientation(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08\x00\x01".to_vec();
+        tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+        tiff.extend_from_slice(&[0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+        tiff.extend_from_slice(&orientation.to_be_bytes());
+        tiff.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        let payload = [EXIF_HEADER, &tiff].concat();
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        data.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        data.extend_from_slice(&payload);
+        // An empty start of scan, followed by the image data
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+        data
+    }
+
+    #[test]
+    fn orientation_is_read_and_stripped() {
+        let data = jpeg_with_orientation(6);
+        assert_eq!(jpeg_orientation(&data), Some(6));
+
+        let stripped = strip_jpeg_metadata(&data).unwrap();
+        assert_eq!(stripped, [0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+        assert_eq!(jpeg_orientation(&stripped), None);
+    }
+}
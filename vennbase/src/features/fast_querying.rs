@@ -1,27 +1,22 @@
 use std::collections::{HashMap, hash_map};
-use std::path::PathBuf;
-use std::fs::File;
 use std::io;
 use serde::{Deserialize, Serialize};
-use std::io::{prelude::*, BufWriter};
 use serde_with::serde_as;
 
+use crate::features::encryption::SideFile;
+
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InvertedIndexMap {
     #[serde(skip)]
-    pub path: PathBuf,
+    pub file: SideFile,
     #[serde_as(as = "Vec<(_, _)>")]
     pub map: HashMap<String, Vec<String>>,
 }
 
 impl InvertedIndexMap {
     fn flush_data(&self) -> io::Result<()> {
-        let file = File::create(&self.path)?;
-        let mut writer = BufWriter::new(file);
-        let serialized_map = serde_json::to_string(&self).unwrap();
-        writer.write_all(serialized_map.as_bytes())?;
-        Ok(())
+        self.file.write_json(self)
     }
 
     pub fn add_tag(&mut self, tag: &str, record_id: uuid::Uuid) {
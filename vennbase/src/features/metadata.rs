@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor};
+use serde::{Deserialize, Serialize};
+
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageResult};
+use image::error::ImageFormatHint;
+use image::codecs::{
+    png::PngDecoder,
+    jpeg::JpegDecoder,
+    bmp::BmpDecoder,
+    gif::GifDecoder,
+    webp::WebPDecoder,
+};
+
+use crate::features::exif::jpeg_orientation;
+use crate::features::similarity::dhash;
+use crate::features::encryption::SideFile;
+
+/// Properties of an image, read from its header when it's saved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    /// Width as the image is displayed, after applying its EXIF orientation
+    pub width: u32,
+    /// Height as the image is displayed, after applying its EXIF orientation
+    pub height: u32,
+    /// Pixel format, like `rgb8` or `rgba16`
+    pub color_type: String,
+    pub animated: bool,
+    /// Perceptual hash used to find similar images, missing in metadata stored before
+    /// hashes were computed or if the image couldn't be decoded
+    #[serde(default)]
+    pub dhash: Option<u64>,
+}
+
+impl ImageMetadata {
+    /// Reads the metadata of an image, returning `None` if its header can't be decoded.
+    ///
+    /// The perceptual hash is computed from `image`, the image decoded with
+    /// `resize::decode_image`, if it could be decoded.
+    pub fn from_image(data: &[u8], format: ImageFormat, image: Option<&DynamicImage>) -> Option<Self> {
+        let read = |data| -> ImageResult<((u32, u32), image::ColorType)> {
+            let cursor = Cursor::new(data);
+            match format {
+                ImageFormat::Png => header_of(PngDecoder::new(cursor)?),
+                ImageFormat::Jpeg => header_of(JpegDecoder::new(cursor)?),
+                ImageFormat::WebP => header_of(WebPDecoder::new(cursor)?),
+                ImageFormat::Bmp => header_of(BmpDecoder::new(cursor)?),
+                ImageFormat::Gif => header_of(GifDecoder::new(cursor)?),
+                _ => Err(image::ImageError::Unsupported(ImageFormatHint::Exact(format).into())),
+            }
+        };
+        let ((width, height), color_type) = read(data).ok()?;
+
+        // Orientations from 5 to 8 rotate the image by 90 degrees
+        let rotated = format == ImageFormat::Jpeg && jpeg_orientation(data).is_some_and(|o| o >= 5);
+        let (width, height) = if rotated { (height, width) } else { (width, height) };
+        Some(ImageMetadata {
+            width,
+            height,
+            color_type: format!("{color_type:?}").to_lowercase(),
+            animated: is_animated(data, format),
+            dhash: image.map(dhash),
+        })
+    }
+
+    /// `portrait`, `landscape` or `square`, as used by `orientation:` queries.
+    pub fn orientation(&self) -> &'static str {
+        match self.width.cmp(&self.height) {
+            std::cmp::Ordering::Less => "portrait",
+            std::cmp::Ordering::Greater => "landscape",
+            std::cmp::Ordering::Equal => "square",
+        }
+    }
+}
+
+fn header_of<'a, D: ImageDecoder<'a>>(decoder: D) -> ImageResult<((u32, u32), image::ColorType)> {
+    Ok((decoder.dimensions(), decoder.color_type()))
+}
+
+/// Animated PNGs have an `acTL` chunk before the image data, and animated WebPs set a flag
+/// in their extended header (`VP8X`). GIFs don't say it anywhere, so their blocks are
+/// walked until a second frame is found.
+fn is_animated(data: &[u8], format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Png => {
+            let mut pos = 8;
+            while let Some(header) = data.get(pos..pos + 8) {
+                let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+                match &header[4..8] {
+                    b"acTL" => return true,
+                    b"IDAT" => return false,
+                    _ => pos += 12 + len,
+                }
+            }
+            false
+        },
+        ImageFormat::WebP => {
+            data.get(12..16) == Some(b"VP8X") && data.get(20).is_some_and(|flags| flags & 0b10 != 0)
+        },
+        ImageFormat::Gif => {
+            // Skip the header, the logical screen descriptor and the global color table
+            let mut pos = 13 + gif_color_table_size(data.get(10));
+            let mut frames = 0;
+            loop {
+                match data.get(pos) {
+                    // Extensions are followed by their label
+                    Some(0x21) => pos += 2,
+                    // Image descriptors are followed by their local color table and the
+                    // minimum code size of the frame data
+                    Some(0x2C) => {
+                        frames += 1;
+                        if frames == 2 {
+                            return true;
+                        }
+                        pos += 10 + gif_color_table_size(data.get(pos + 9)) + 1;
+                    },
+                    _ => return false,
+                }
+                // Skip the sub-blocks of the extension or the frame
+                while let Some(&len) = data.get(pos) {
+                    pos += 1 + len as usize;
+                    if len == 0 {
+                        break;
+                    }
+                }
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Length of the color table of a GIF, from the flags of the descriptor it follows.
+fn gif_color_table_size(flags: Option<&u8>) -> usize {
+    match flags.copied().unwrap_or_default() {
+        flags if flags & 0x80 != 0 => 3 << ((flags & 0x07) + 1),
+        _ => 0,
+    }
+}
+
+/// Metadata of the images of the database, by record id.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetadataMap {
+    #[serde(skip)]
+    pub file: SideFile,
+    pub records: HashMap<uuid::Uuid, ImageMetadata>,
+    /// Images whose metadata or perceptual hash couldn't be read, so they are not decoded
+    /// again when the database is opened
+    #[serde(default)]
+    pub undecodable: HashSet<uuid::Uuid>,
+}
+
+impl MetadataMap {
+    /// Loads the metadata from `file`. Databases created before metadata existed don't
+    /// have the file, so an empty map is returned in that case.
+    pub fn from_file(file: SideFile) -> io::Result<Self> {
+        if !file.exists() {
+            return Ok(MetadataMap { file, records: HashMap::new(), undecodable: HashSet::new() });
+        }
+        let mut metadata = file.read_json::<MetadataMap>()?;
+        metadata.file = file;
+        Ok(metadata)
+    }
+
+    pub fn flush_data(&self) -> io::Result<()> {
+        self.file.write_json(self)
+    }
+
+    pub fn get(&self, record_id: &uuid::Uuid) -> Option<&ImageMetadata> {
+        self.records.get(record_id)
+    }
+
+    pub fn is_undecodable(&self, record_id: &uuid::Uuid) -> bool {
+        self.undecodable.contains(record_id)
+    }
+
+    /// Stores the metadata of an image. `None`, or metadata without a perceptual hash,
+    /// marks the image as undecodable.
+    pub fn insert(&mut self, record_id: uuid::Uuid, metadata: Option<ImageMetadata>) -> io::Result<()> {
+        self.insert_unflushed(record_id, metadata);
+        self.flush_data()
+    }
+
+    /// Like `insert`, but the file is not written until `flush_data` is called. Used to
+    /// store many images at once.
+    pub fn insert_unflushed(&mut self, record_id: uuid::Uuid, metadata: Option<ImageMetadata>) {
+        if metadata.as_ref().is_some_and(|metadata| metadata.dhash.is_some()) {
+            self.undecodable.remove(&record_id);
+        }
+        else {
+            self.undecodable.insert(record_id);
+        }
+        match metadata {
+            Some(metadata) => self.records.insert(record_id, metadata),
+            None => self.records.remove(&record_id),
+        };
+    }
+
+    pub fn remove(&mut self, record_id: &uuid::Uuid) -> io::Result<()> {
+        let removed = self.records.remove(record_id).is_some();
+        if self.undecodable.remove(record_id) || removed {
+            self.flush_data()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn gifs_with_several_frames_are_animated() {
+        let gif = |frames: &[[u8; 4]]| {
+            let mut data = Vec::new();
+            {
+                let mut encoder = GifEncoder::new(&mut data);
+                for color in frames {
+                    let frame = Frame::from_parts(RgbaImage::from_pixel(8, 4, Rgba(*color)), 0, 0, Delay::from_numer_denom_ms(100, 1));
+                    encoder.encode_frame(frame).unwrap();
+                }
+            }
+            data
+        };
+        let still = gif(&[[255, 0, 0, 255]]);
+        let animation = gif(&[[255, 0, 0, 255], [0, 0, 255, 255]]);
+        assert!(!is_animated(&still, ImageFormat::Gif));
+        assert!(is_animated(&animation, ImageFormat::Gif));
+        // Truncated GIFs are not
+        assert!(!is_animated(&animation[..animation.len() / 2], ImageFormat::Gif));
+
+        let metadata = ImageMetadata::from_image(&animation, ImageFormat::Gif, None).unwrap();
+        assert_eq!((metadata.width, metadata.height, metadata.animated, metadata.dhash), (8, 4, true, None));
+    }
+}
@@ -1,3 +1,13 @@
 pub mod resize;
-// pub mod cache;
+pub mod exif;
+pub mod metadata;
+pub mod similarity;
+pub mod sniffing;
+pub mod dedup;
+pub mod uploads;
+pub mod compression;
+pub mod encryption;
+pub mod cache;
 pub mod fast_querying;
+pub mod views;
+pub mod shared_buffers;
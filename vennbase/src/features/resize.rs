@@ -2,30 +2,34 @@ use std::io::{BufWriter, IntoInnerError};
 use std::{num::NonZeroU32, io::Cursor};
 
 use fast_image_resize as fr;
-use fr::{ImageBufferError, MulDivImageError, DifferentTypesOfPixelsError};
+use fr::{ImageBufferError, MulDivImageError, DifferentTypesOfPixelsError, CropBoxError};
 use image::error::{UnsupportedError, ImageFormatHint, UnsupportedErrorKind};
-use image::{ColorType, ImageEncoder, ImageResult};
+use image::{AnimationDecoder, ColorType, DynamicImage, Frame, ImageEncoder, ImageResult, RgbaImage};
 use image::{
     io::Reader as ImageReader,
     ImageError,
     ImageFormat
 };
 use image::codecs::{
-    png::PngEncoder,
+    png::{PngEncoder, CompressionType, FilterType as PngFilterType},
     jpeg::JpegEncoder,
     bmp::BmpEncoder,
-    webp::WebPEncoder
+    gif::{GifDecoder, GifEncoder, Repeat},
+    webp::{WebPEncoder, WebPQuality}
 };
 
+use serde::Deserialize;
+
 use crate::db::types::MimeType;
+use crate::features::exif::{apply_orientation, jpeg_orientation};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum Resize {
     Auto,
     Dimension(NonZeroU32),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Dimensions(Resize, Resize);
 
 #[derive(Debug)]
@@ -55,6 +59,354 @@ impl Dimensions {
 
         Ok(Dimensions(width, height))
     }
+
+    /// Keeps the dimensions of the original image, like `autoxauto`.
+    pub fn original() -> Self {
+        Dimensions(Resize::Auto, Resize::Auto)
+    }
+}
+
+/// Resampling algorithm used to resize images
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    #[default]
+    Nearest,
+    Bilinear,
+    CatmullRom,
+    Lanczos3,
+    Supersample,
+}
+
+#[derive(Debug)]
+pub struct InvalidResizeFilter;
+
+impl ResizeFilter {
+    pub fn from_name(name: &str) -> Result<Self, InvalidResizeFilter> {
+        match name {
+            "nearest" => Ok(ResizeFilter::Nearest),
+            "bilinear" => Ok(ResizeFilter::Bilinear),
+            "catmullrom" => Ok(ResizeFilter::CatmullRom),
+            "lanczos3" => Ok(ResizeFilter::Lanczos3),
+            "supersample" => Ok(ResizeFilter::Supersample),
+            _ => Err(InvalidResizeFilter),
+        }
+    }
+
+    fn algorithm(&self) -> fr::ResizeAlg {
+        match self {
+            ResizeFilter::Nearest => fr::ResizeAlg::Nearest,
+            ResizeFilter::Bilinear => fr::ResizeAlg::Convolution(fr::FilterType::Bilinear),
+            ResizeFilter::CatmullRom => fr::ResizeAlg::Convolution(fr::FilterType::CatmullRom),
+            ResizeFilter::Lanczos3 => fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3),
+            ResizeFilter::Supersample => fr::ResizeAlg::SuperSampling(fr::FilterType::Bilinear, 2),
+        }
+    }
+}
+
+/// How an image is fitted into explicit `WxH` dimensions.
+///
+/// When one of the sides is `auto`, the aspect ratio is always kept and the mode has
+/// no effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ResizeMode {
+    /// Resizes to exactly `WxH`, distorting the image if needed
+    #[default]
+    Stretch,
+    /// Scales the image to fit inside `WxH` (contain)
+    Fit,
+    /// Scales the image to cover `WxH`, and crops what's left out (cover)
+    Fill,
+    /// Cuts a `WxH` region of the image without scaling it
+    Crop,
+    /// Like `Fit`, but fills the rest of `WxH` with transparent pixels
+    Pad,
+}
+
+#[derive(Debug)]
+pub struct InvalidResizeMode;
+
+impl ResizeMode {
+    pub fn from_name(name: &str) -> Result<Self, InvalidResizeMode> {
+        match name {
+            "stretch" => Ok(ResizeMode::Stretch),
+            "fit" => Ok(ResizeMode::Fit),
+            "fill" => Ok(ResizeMode::Fill),
+            "crop" => Ok(ResizeMode::Crop),
+            "pad" => Ok(ResizeMode::Pad),
+            _ => Err(InvalidResizeMode),
+        }
+    }
+}
+
+/// Which part of the image is kept when cropping, or where it's placed when padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+}
+
+#[derive(Debug)]
+pub struct InvalidGravity;
+
+impl Gravity {
+    pub fn from_name(name: &str) -> Result<Self, InvalidGravity> {
+        match name {
+            "center" => Ok(Gravity::Center),
+            "north" => Ok(Gravity::North),
+            "south" => Ok(Gravity::South),
+            "east" => Ok(Gravity::East),
+            "west" => Ok(Gravity::West),
+            "northeast" => Ok(Gravity::NorthEast),
+            "northwest" => Ok(Gravity::NorthWest),
+            "southeast" => Ok(Gravity::SouthEast),
+            "southwest" => Ok(Gravity::SouthWest),
+            _ => Err(InvalidGravity),
+        }
+    }
+
+    /// Horizontal and vertical position, from 0 (left/top) to 1 (right/bottom)
+    fn position(&self) -> (f32, f32) {
+        match self {
+            Gravity::Center => (0.5, 0.5),
+            Gravity::North => (0.5, 0.0),
+            Gravity::South => (0.5, 1.0),
+            Gravity::East => (1.0, 0.5),
+            Gravity::West => (0.0, 0.5),
+            Gravity::NorthEast => (1.0, 0.0),
+            Gravity::NorthWest => (0.0, 0.0),
+            Gravity::SouthEast => (1.0, 1.0),
+            Gravity::SouthWest => (0.0, 1.0),
+        }
+    }
+}
+
+/// Compression level of PNG images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+#[derive(Debug)]
+pub struct InvalidPngCompression;
+
+impl PngCompression {
+    pub fn from_name(name: &str) -> Result<Self, InvalidPngCompression> {
+        match name {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            _ => Err(InvalidPngCompression),
+        }
+    }
+
+    fn compression_type(&self) -> CompressionType {
+        match self {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+}
+
+/// Filter applied to the scanlines of PNG images before compressing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    /// Chooses the best filter for each scanline
+    #[default]
+    Adaptive,
+}
+
+#[derive(Debug)]
+pub struct InvalidPngFilter;
+
+impl PngFilter {
+    pub fn from_name(name: &str) -> Result<Self, InvalidPngFilter> {
+        match name {
+            "none" => Ok(PngFilter::None),
+            "sub" => Ok(PngFilter::Sub),
+            "up" => Ok(PngFilter::Up),
+            "avg" => Ok(PngFilter::Avg),
+            "paeth" => Ok(PngFilter::Paeth),
+            "adaptive" => Ok(PngFilter::Adaptive),
+            _ => Err(InvalidPngFilter),
+        }
+    }
+
+    fn filter_type(&self) -> PngFilterType {
+        match self {
+            PngFilter::None => PngFilterType::NoFilter,
+            PngFilter::Sub => PngFilterType::Sub,
+            PngFilter::Up => PngFilterType::Up,
+            PngFilter::Avg => PngFilterType::Avg,
+            PngFilter::Paeth => PngFilterType::Paeth,
+            PngFilter::Adaptive => PngFilterType::Adaptive,
+        }
+    }
+}
+
+/// Encoder settings of the resulting images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EncodeOptions {
+    /// From 1 (smallest) to 100 (best looking)
+    pub jpeg_quality: u8,
+    /// From 1 (smallest) to 100 (best looking)
+    pub webp_quality: u8,
+    pub png_compression: PngCompression,
+    pub png_filter: PngFilter,
+}
+
+impl Default for EncodeOptions {
+    /// The defaults of the `image` crate encoders
+    fn default() -> Self {
+        EncodeOptions {
+            jpeg_quality: 75,
+            webp_quality: WebPQuality::DEFAULT,
+            png_compression: PngCompression::Default,
+            png_filter: PngFilter::Adaptive,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidQuality;
+
+/// Parses a quality for lossy formats, from 1 to 100.
+pub fn quality_from_str(quality: &str) -> Result<u8, InvalidQuality> {
+    match quality.parse::<u8>() {
+        Ok(quality @ 1..=100) => Ok(quality),
+        _ => Err(InvalidQuality),
+    }
+}
+
+/// Everything that determines how an image is resized.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResizeOptions {
+    pub dims: Dimensions,
+    pub filter: ResizeFilter,
+    pub mode: ResizeMode,
+    pub gravity: Gravity,
+    /// Format of the resulting image, the source format if not given
+    pub output: Option<ImageFormat>,
+    pub encoding: EncodeOptions,
+    /// Whether animated images are reduced to their first frame
+    pub first_frame: bool,
+}
+
+/// A `(left, top, width, height)` rectangle, in pixels.
+type Region = (u32, u32, u32, u32);
+
+/// Where the pixels of the source image end up in the destination image.
+#[derive(Debug, PartialEq, Eq)]
+struct ResizePlan {
+    // Part of the source image that is resized
+    src: Region,
+    // Size of the whole destination image
+    dst_size: (u32, u32),
+    // Part of the destination image that receives the resized pixels
+    dst: Region,
+}
+
+/// Scales `value` by `num / den`, never going below 1 pixel.
+fn scale(value: u32, num: u32, den: u32) -> u32 {
+    ((value as f64 * num as f64 / den as f64).round() as u32).max(1)
+}
+
+/// Places a `part`-sized segment inside a `total`-sized one at the given position.
+fn offset(total: u32, part: u32, position: f32) -> u32 {
+    ((total - part) as f32 * position).round() as u32
+}
+
+fn place(outer: (u32, u32), inner: (u32, u32), gravity: Gravity) -> Region {
+    let (x, y) = gravity.position();
+    (offset(outer.0, inner.0, x), offset(outer.1, inner.1, y), inner.0, inner.1)
+}
+
+impl ResizeOptions {
+    fn plan(&self, (width, height): (u32, u32)) -> ResizePlan {
+        let whole = (0, 0, width, height);
+        let (new_width, new_height) = match (&self.dims.0, &self.dims.1) {
+            // 'autoxauto' has no effect
+            (Resize::Auto, Resize::Auto) => (width, height),
+            (Resize::Dimension(w), Resize::Auto) => (w.get(), scale(height, w.get(), width)),
+            (Resize::Auto, Resize::Dimension(h)) => (scale(width, h.get(), height), h.get()),
+            (Resize::Dimension(w), Resize::Dimension(h)) => (w.get(), h.get()),
+        };
+        let explicit = matches!(self.dims, Dimensions(Resize::Dimension(_), Resize::Dimension(_)));
+        let mode = if explicit { self.mode } else { ResizeMode::Stretch };
+        // Size of the whole image once it's scaled to fit inside the new dimensions
+        let fitted = if width as u64 * new_height as u64 > height as u64 * new_width as u64 {
+            (new_width, scale(height, new_width, width))
+        } else {
+            (scale(width, new_height, height), new_height)
+        };
+
+        match mode {
+            ResizeMode::Stretch => ResizePlan {
+                src: whole,
+                dst_size: (new_width, new_height),
+                dst: (0, 0, new_width, new_height),
+            },
+            ResizeMode::Fit => ResizePlan {
+                src: whole,
+                dst_size: fitted,
+                dst: (0, 0, fitted.0, fitted.1),
+            },
+            ResizeMode::Fill => {
+                // Biggest region of the source image with the aspect ratio of the new dimensions
+                let covered = if width as u64 * new_height as u64 > height as u64 * new_width as u64 {
+                    (scale(height, new_width, new_height).min(width), height)
+                } else {
+                    (width, scale(width, new_height, new_width).min(height))
+                };
+                ResizePlan {
+                    src: place((width, height), covered, self.gravity),
+                    dst_size: (new_width, new_height),
+                    dst: (0, 0, new_width, new_height),
+                }
+            },
+            ResizeMode::Crop => {
+                let cropped = (new_width.min(width), new_height.min(height));
+                ResizePlan {
+                    src: place((width, height), cropped, self.gravity),
+                    dst_size: cropped,
+                    dst: (0, 0, cropped.0, cropped.1),
+                }
+            },
+            ResizeMode::Pad => ResizePlan {
+                src: whole,
+                dst_size: (new_width, new_height),
+                dst: place((new_width, new_height), fitted, self.gravity),
+            },
+        }
+    }
+}
+
+fn crop_box((left, top, width, height): Region) -> fr::CropBox {
+    fr::CropBox {
+        left,
+        top,
+        width: NonZeroU32::new(width).expect("regions to be at least 1 pixel wide"),
+        height: NonZeroU32::new(height).expect("regions to be at least 1 pixel high"),
+    }
 }
 
 #[derive(Debug)]
@@ -64,16 +416,29 @@ pub enum ResizeError {
     ImageError(ImageError),
     MulDivImageError(MulDivImageError),
     DifferentTypesOfPixelsError(DifferentTypesOfPixelsError),
-    BufferFlushError(IntoInnerError<BufWriter<Vec<u8>>>)
+    BufferFlushError(IntoInnerError<BufWriter<Vec<u8>>>),
+    CropBoxError(CropBoxError),
 }
 
-fn encode_image_with_format(image_buffer: &[u8], dims: (u32, u32), format: ImageFormat) -> ImageResult<BufWriter<Vec<u8>>> {
+/// From 1 to 30, the GIF encoder's default of 1 is too slow for images of any size
+const GIF_ENCODING_SPEED: i32 = 10;
+
+fn encode_image_with_format(
+    image_buffer: &[u8],
+    dims: (u32, u32),
+    format: ImageFormat,
+    encoding: &EncodeOptions
+) -> ImageResult<BufWriter<Vec<u8>>> {
     let mut result_buf = BufWriter::new(Vec::new());
 
     match format {
         ImageFormat::Png => {
             // Write destination image as PNG-file
-            PngEncoder::new(&mut result_buf)
+            PngEncoder::new_with_quality(
+                &mut result_buf,
+                encoding.png_compression.compression_type(),
+                encoding.png_filter.filter_type()
+            )
                 .write_image(
                     image_buffer,
                     dims.0,
@@ -83,7 +448,7 @@ fn encode_image_with_format(image_buffer: &[u8], dims: (u32, u32), format: Image
             Ok(result_buf)
         },
         ImageFormat::Jpeg => {
-            JpegEncoder::new(&mut result_buf)
+            JpegEncoder::new_with_quality(&mut result_buf, encoding.jpeg_quality)
                 .write_image(
                     image_buffer,
                     dims.0,
@@ -93,7 +458,7 @@ fn encode_image_with_format(image_buffer: &[u8], dims: (u32, u32), format: Image
             Ok(result_buf)
         },
         ImageFormat::WebP => {
-            WebPEncoder::new(&mut result_buf)
+            WebPEncoder::new_with_quality(&mut result_buf, WebPQuality::lossy(encoding.webp_quality))
                 .write_image(
                     image_buffer,
                     dims.0,
@@ -112,6 +477,16 @@ fn encode_image_with_format(image_buffer: &[u8], dims: (u32, u32), format: Image
                 )?;
             Ok(result_buf)
         },
+        ImageFormat::Gif => {
+            GifEncoder::new_with_speed(&mut result_buf, GIF_ENCODING_SPEED)
+                .encode(
+                    image_buffer,
+                    dims.0,
+                    dims.1,
+                    ColorType::Rgba8,
+                )?;
+            Ok(result_buf)
+        },
         _ => {
             Err(ImageError::Unsupported(
                 UnsupportedError::from_format_and_kind(
@@ -123,92 +498,197 @@ fn encode_image_with_format(image_buffer: &[u8], dims: (u32, u32), format: Image
     }
 }
 
+/// Formats that can be both decoded and encoded by `resize_image`.
+fn is_supported_format(format: ImageFormat) -> bool {
+    matches!(format,
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Bmp | ImageFormat::Gif
+    )
+}
+
 pub fn is_resizable_format(mimetype: &MimeType) -> bool {
     match ImageFormat::from_mime_type(mimetype.as_str()) {
-        Some(format) => matches!(format,
-            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Bmp
-        ),
+        Some(format) => is_supported_format(format),
         None => false,
     }
 }
 
+/// Parses the format images can be converted into, either as a mimetype (`image/webp`)
+/// or as an extension (`webp`).
+pub fn output_format_from_name(name: &str) -> Option<ImageFormat> {
+    ImageFormat::from_mime_type(name)
+        .or_else(|| ImageFormat::from_extension(name))
+        .filter(|format| is_supported_format(*format))
+}
+
 
 /// Interpretes a well-formed set of bytes, guessing its  image by using the fast_image_resize crate
 ///
+/// Animated GIFs are resized frame by frame, keeping the delay of every frame, unless
+/// they are converted to another format or only the first frame is requested.
+///
 /// # Panics
 /// If any of the given size is zero
 ///
-pub fn resize_image(data: &Vec<u8>, format: ImageFormat, new_dims: &Dimensions) -> Result<Vec<u8>, ResizeError> {
-    // Read source image from file
+pub fn resize_image(data: &[u8], format: ImageFormat, options: &ResizeOptions) -> Result<Vec<u8>, ResizeError> {
+    if keeps_animation(format, options) {
+        return resize_animation(data, options);
+    }
+    resize_decoded_image(&decode_image(data, format)?, data, format, options)
+}
+
+/// Like `resize_image`, for an image that was already decoded with `decode_image`.
+/// Animated GIFs are still resized from `data`, since only their first frame is decoded.
+pub fn resize_decoded_image(
+    img: &DynamicImage,
+    data: &[u8],
+    format: ImageFormat,
+    options: &ResizeOptions
+) -> Result<Vec<u8>, ResizeError> {
+    if keeps_animation(format, options) {
+        return resize_animation(data, options);
+    }
+    let output_format = options.output.unwrap_or(format);
+    let dst_image = resize_pixels(img.to_rgba8(), options)?;
+    match encode_image_with_format(dst_image.as_raw(), dst_image.dimensions(), output_format, &options.encoding) {
+        Ok(img_buffer) => Ok(
+            img_buffer.into_inner().map_err(ResizeError::BufferFlushError)?
+        ),
+        Err(e) => Err(ResizeError::ImageError(e)),
+    }
+}
+
+/// Decodes an image as it's displayed, that is, with its EXIF orientation applied.
+pub fn decode_image(data: &[u8], format: ImageFormat) -> ImageResult<DynamicImage> {
     let img = ImageReader::with_format(Cursor::new(data), format).decode()?;
+    // Phone cameras store the pixels as they were captured and tell viewers how to rotate
+    // them. Since the resulting image has no EXIF metadata, they must be rotated here
+    Ok(match format {
+        ImageFormat::Jpeg => match jpeg_orientation(data) {
+            Some(orientation) => apply_orientation(img, orientation),
+            None => img,
+        },
+        _ => img,
+    })
+}
+
+/// Decodes the image of a record, see `decode_image`. Returns `None` if the mimetype is
+/// not a format that can be resized.
+pub fn decode_record_image(mimetype: &MimeType, data: &[u8]) -> Option<ImageResult<DynamicImage>> {
+    let format = ImageFormat::from_mime_type(mimetype.as_str()).filter(|format| is_supported_format(*format))?;
+    Some(decode_image(data, format))
+}
+
+/// Whether the resized image is an animated GIF, resized frame by frame.
+fn keeps_animation(format: ImageFormat, options: &ResizeOptions) -> bool {
+    format == ImageFormat::Gif && options.output.unwrap_or(format) == ImageFormat::Gif && !options.first_frame
+}
+
+/// Reads how many times a GIF loops from its `NETSCAPE2.0` application extension, which
+/// comes before the first frame. GIFs without it are played once.
+fn gif_repeat(data: &[u8]) -> Repeat {
+    // Skip the header, the logical screen descriptor and the global color table
+    let flags = data.get(10).copied().unwrap_or_default();
+    let mut pos = 13 + if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 };
+    // Only extensions can come before the first frame
+    while data.get(pos) == Some(&0x21) {
+        let label = data.get(pos + 1).copied();
+        pos += 2;
+        let is_netscape = label == Some(0xFF) && data.get(pos..pos + 12) == Some(b"\x0bNETSCAPE2.0");
+        if is_netscape {
+            if let Some(&[3, 1, low, high]) = data.get(pos + 12..pos + 16) {
+                return match u16::from_le_bytes([low, high]) {
+                    0 => Repeat::Infinite,
+                    n => Repeat::Finite(n),
+                };
+            }
+        }
+        // Skip the sub-blocks of the extension
+        while let Some(&len) = data.get(pos) {
+            pos += 1 + len as usize;
+            if len == 0 {
+                break;
+            }
+        }
+    }
+    Repeat::Finite(0)
+}
+
+/// Resizes every frame of a GIF, keeping the original frame delays and loop count.
+fn resize_animation(data: &[u8], options: &ResizeOptions) -> Result<Vec<u8>, ResizeError> {
+    // The decoder composes every frame over the previous ones, so all of them have the
+    // size of the whole image
+    let frames = GifDecoder::new(Cursor::new(data))?.into_frames().collect_frames()?;
+
+    let mut result_buf = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut result_buf, GIF_ENCODING_SPEED);
+        encoder.set_repeat(gif_repeat(data))?;
+        for frame in frames {
+            let delay = frame.delay();
+            let resized = resize_pixels(frame.into_buffer(), options)?;
+            encoder.encode_frame(Frame::from_parts(resized, 0, 0, delay))?;
+        }
+        // The GIF trailer is written when the encoder is dropped
+    }
+    Ok(result_buf)
+}
+
+/// Resizes the pixels of a decoded image following the options' plan.
+fn resize_pixels(img: RgbaImage, options: &ResizeOptions) -> Result<RgbaImage, ResizeError> {
+    let (src_width, src_height) = img.dimensions();
     let (width, height) = (
-        NonZeroU32::new(img.width()).expect("To be positive"),
-        NonZeroU32::new(img.height()).expect("To be positive")
+        NonZeroU32::new(src_width).expect("To be positive"),
+        NonZeroU32::new(src_height).expect("To be positive")
     );
 
     let mut src_image = fr::Image::from_vec_u8(
         width,
         height,
-        img.to_rgba8().into_raw(),
+        img.into_raw(),
         fr::PixelType::U8x4,
     )?;
 
     // Multiple RGB channels of source image by alpha channel
-    // (not required for the Nearest algorithm)
+    // (not required for the Nearest algorithm, since it doesn't mix pixels)
+    let needs_alpha_mul_div = options.filter != ResizeFilter::Nearest;
     let alpha_mul_div = fr::MulDiv::default();
-    alpha_mul_div
-        .multiply_alpha_inplace(&mut src_image.view_mut())
-        .map_err(ResizeError::MulDivImageError)?;
-
-    let aspect_ratio = img.width() as f32 / img.height() as f32;
-    // Create container for data of destination image
-    let (dst_width, dst_height) = match new_dims.0 {
-        Resize::Auto => {
-            match new_dims.1 {
-                Resize::Auto => (width, height), // 'autoxauto' has no effect
-                Resize::Dimension(h) => {
-                    let w = NonZeroU32::new((h.get() as f32 * aspect_ratio) as u32).unwrap_or(
-                        NonZeroU32::new(1).unwrap()
-                    );
-                    (w, h)
-                },
-            }
-        },
-        Resize::Dimension(h) => {
-            let w = match new_dims.1 {
-                Resize::Auto => NonZeroU32::new((h.get() as f32 * aspect_ratio) as u32).unwrap_or(
-                    NonZeroU32::new(1).unwrap()
-                ),
-                Resize::Dimension(h2) => h2,
-            };
-            (w, h)
-        },
-    };
+    if needs_alpha_mul_div {
+        alpha_mul_div
+            .multiply_alpha_inplace(&mut src_image.view_mut())
+            .map_err(ResizeError::MulDivImageError)?;
+    }
+
+    let plan = options.plan((src_width, src_height));
+    let (dst_width, dst_height) = (
+        NonZeroU32::new(plan.dst_size.0).expect("To be positive"),
+        NonZeroU32::new(plan.dst_size.1).expect("To be positive")
+    );
+    // Padded areas are left transparent
     let mut dst_image = fr::Image::new(
         dst_width,
         dst_height,
         src_image.pixel_type(),
     );
 
+    let mut src_view = src_image.view();
+    src_view.set_crop_box(crop_box(plan.src)).map_err(ResizeError::CropBoxError)?;
     // Get mutable view of destination image data
-    let mut dst_view = dst_image.view_mut();
+    let mut dst_view = dst_image.view_mut().crop(crop_box(plan.dst)).map_err(ResizeError::CropBoxError)?;
 
     // Create Resizer instance and resize source image
     // into buffer of destination image
-    let mut resizer = fr::Resizer::new(fr::ResizeAlg::Nearest);
-    resizer.resize(&src_image.view(), &mut dst_view)
+    let mut resizer = fr::Resizer::new(options.filter.algorithm());
+    resizer.resize(&src_view, &mut dst_view)
         .map_err(ResizeError::DifferentTypesOfPixelsError)?;
 
     // Divide RGB channels of destination image by alpha
-    alpha_mul_div.divide_alpha_inplace(&mut dst_view)
-        .map_err(ResizeError::MulDivImageError)?;
-
-    match encode_image_with_format(dst_image.buffer(), (dst_width.into(), dst_height.into()),format) {
-        Ok(img_buffer) => Ok(
-            img_buffer.into_inner().map_err(ResizeError::BufferFlushError)?
-        ),
-        Err(e) => Err(ResizeError::ImageError(e)),
+    if needs_alpha_mul_div {
+        alpha_mul_div.divide_alpha_inplace(&mut dst_view)
+            .map_err(ResizeError::MulDivImageError)?;
     }
+
+    Ok(RgbaImage::from_raw(dst_width.get(), dst_height.get(), dst_image.into_vec())
+        .expect("the buffer to hold every pixel"))
 }
 
 impl From<ImageBufferError> for ResizeError {
@@ -235,6 +715,7 @@ mod tests {
     use std::fs::File;
 
     use super::*;
+    use crate::db::vennbase::testing::{png, resize_options};
 
     fn parse_png_or_fail(path: &str) -> io::Result<()> {
         let file = File::open(path)?;
@@ -245,17 +726,119 @@ mod tests {
         let image = resize_image(
             &data,
             ImageFormat::Png,
-            &Dimensions::from_dim_str("200xauto").unwrap()
+            &ResizeOptions { filter: ResizeFilter::Lanczos3, ..resize_options("200xauto") }
         ).unwrap();
 
-        assert!(image.len() > 0);
+        assert!(!image.is_empty());
         Ok(())
     }
 
+    fn plan(dims: &str, mode: ResizeMode, gravity: Gravity, src: (u32, u32)) -> ResizePlan {
+        ResizeOptions { mode, gravity, ..resize_options(dims) }.plan(src)
+    }
+
+    #[test]
+    fn auto_sides_keep_the_aspect_ratio() {
+        let stretch = ResizeMode::Stretch;
+        assert_eq!(plan("50xauto", stretch, Gravity::Center, (200, 100)).dst_size, (50, 25));
+        assert_eq!(plan("autox50", stretch, Gravity::Center, (200, 100)).dst_size, (100, 50));
+        // The mode only matters when both sides are explicit
+        assert_eq!(plan("50xauto", ResizeMode::Crop, Gravity::Center, (200, 100)).dst_size, (50, 25));
+    }
+
+    #[test]
+    fn explicit_dimensions_follow_the_mode() {
+        let src = (200, 100);
+        let fit = plan("50x50", ResizeMode::Fit, Gravity::Center, src);
+        assert_eq!((fit.src, fit.dst_size), ((0, 0, 200, 100), (50, 25)));
+
+        let fill = plan("50x50", ResizeMode::Fill, Gravity::Center, src);
+        assert_eq!((fill.src, fill.dst_size), ((50, 0, 100, 100), (50, 50)));
+
+        let fill = plan("50x50", ResizeMode::Fill, Gravity::East, src);
+        assert_eq!(fill.src, (100, 0, 100, 100));
+
+        let crop = plan("50x300", ResizeMode::Crop, Gravity::NorthWest, src);
+        assert_eq!((crop.src, crop.dst_size), ((0, 0, 50, 100), (50, 100)));
+
+        let pad = plan("50x50", ResizeMode::Pad, Gravity::South, src);
+        assert_eq!((pad.dst_size, pad.dst), ((50, 50), (0, 25, 50, 25)));
+    }
+
+    #[test]
+    fn output_formats_are_named_by_mimetype_or_extension() {
+        assert_eq!(output_format_from_name("image/jpeg"), Some(ImageFormat::Jpeg));
+        assert_eq!(output_format_from_name("webp"), Some(ImageFormat::WebP));
+        assert_eq!(output_format_from_name("text/plain"), None);
+    }
+
+    #[test]
+    fn images_are_converted_to_the_output_format() {
+        let options = ResizeOptions {
+            output: Some(ImageFormat::Jpeg),
+            ..resize_options("4x4")
+        };
+        let image = resize_image(&png(8, 8), ImageFormat::Png, &options).unwrap();
+        assert_eq!(image::guess_format(&image).unwrap(), ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn lower_quality_gives_smaller_images() {
+        let encode = |jpeg_quality| {
+            let options = ResizeOptions {
+                output: Some(ImageFormat::Jpeg),
+                encoding: EncodeOptions { jpeg_quality, ..EncodeOptions::default() },
+                ..resize_options("64x64")
+            };
+            resize_image(&png(64, 64), ImageFormat::Png, &options).unwrap().len()
+        };
+        assert!(encode(10) < encode(95));
+
+        assert_eq!(quality_from_str("100").unwrap(), 100);
+        assert!(quality_from_str("0").is_err());
+        assert!(quality_from_str("101").is_err());
+    }
+
     #[test]
     fn png_being_parsed_correctly() -> io::Result<()> {
         parse_png_or_fail("../data/blossom.png")?;
 
         Ok(())
     }
+
+    #[test]
+    fn gif_frames_keep_their_delays() {
+        let animation = |repeat: Repeat| {
+            let mut data = Vec::new();
+            {
+                let mut encoder = GifEncoder::new(&mut data);
+                encoder.set_repeat(repeat).unwrap();
+                for (color, ms) in [([255, 0, 0, 255], 100), ([0, 0, 255, 255], 300)] {
+                    let delay = image::Delay::from_numer_denom_ms(ms, 1);
+                    encoder.encode_frame(Frame::from_parts(RgbaImage::from_pixel(40, 20, image::Rgba(color)), 0, 0, delay)).unwrap();
+                }
+            }
+            data
+        };
+        let data = animation(Repeat::Finite(3));
+        let mut options = resize_options("10xauto");
+        let decode = |data: &[u8]| GifDecoder::new(Cursor::new(data)).unwrap().into_frames().collect_frames().unwrap();
+
+        let frames = decode(&resize_image(&data, ImageFormat::Gif, &options).unwrap());
+        let delays: Vec<_> = frames.iter().map(|frame| frame.delay().numer_denom_ms()).collect();
+        assert_eq!(delays, [(100, 1), (300, 1)]);
+        assert_eq!(frames[1].buffer().dimensions(), (10, 5));
+
+        // The loop count is kept too, including GIFs that are played once
+        let loops = |data: &[u8]| match gif_repeat(&resize_image(data, ImageFormat::Gif, &options).unwrap()) {
+            Repeat::Finite(n) => Some(n),
+            Repeat::Infinite => None,
+        };
+        assert_eq!(loops(&data), Some(3));
+        assert_eq!(loops(&animation(Repeat::Infinite)), None);
+        assert_eq!(loops(&animation(Repeat::Finite(0))), Some(0));
+
+        options.first_frame = true;
+        assert_eq!(decode(&resize_image(&data, ImageFormat::Gif, &options).unwrap()).len(), 1);
+    }
 }
@@ -0,0 +1,90 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::db::partition::{Partition, RecordInformation};
+use crate::features::cache::{CacheStats, LRUCache, LRUCacheBuilder};
+
+/// Partition files are cached in pages of this size (in bytes).
+pub const PAGE_SIZE: u64 = 8192;
+
+/// A memory-bounded pool of partition pages, similar to PostgreSQL's `shared_buffers`.
+///
+/// Pages are identified by the partition file and their number, and the least recently
+/// used ones are evicted when the pool is full. Records are read by stitching together
+/// the pages that cover them, so hot records are served without touching the disk.
+pub struct SharedBuffers {
+    pages: LRUCache<(PathBuf, u64), Arc<Vec<u8>>>,
+    max_record_size: u64,
+}
+
+impl SharedBuffers {
+    pub fn new(capacity: usize, max_record_size: usize) -> Self {
+        SharedBuffers {
+            pages: LRUCacheBuilder::<Arc<Vec<u8>>>::new()
+                .with_max_size(capacity)
+                .with_calc_size(|page| page.len())
+                .build(),
+            max_record_size: max_record_size as u64,
+        }
+    }
+
+    /// Whether a record of the given size should be read through the pool.
+    pub fn can_buffer(&self, size: u64) -> bool {
+        size <= self.max_record_size
+    }
+
+    /// Reads `len` bytes starting at `start` from the partition file, loading the missing
+    /// pages from disk.
+    pub fn read(&self, partition: &Partition, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len as usize);
+        if len == 0 {
+            return Ok(data);
+        }
+        let end = start + len;
+        for page_number in start / PAGE_SIZE..=(end - 1) / PAGE_SIZE {
+            let key = (partition.file_path().to_path_buf(), page_number);
+            let page = match self.pages.get(&key) {
+                Some(page) => page,
+                None => {
+                    let page = Arc::new(partition.read_at(page_number * PAGE_SIZE, PAGE_SIZE)?);
+                    self.pages.save(key, Arc::clone(&page));
+                    page
+                },
+            };
+            let page_start = page_number * PAGE_SIZE;
+            let from = start.max(page_start) - page_start;
+            let to = (end.min(page_start + PAGE_SIZE) - page_start).min(page.len() as u64);
+            if from >= to {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Record exceeds the partition file"));
+            }
+            data.extend_from_slice(&page[from as usize..to as usize]);
+        }
+        Ok(data)
+    }
+
+    /// Evicts the pages of a partition file that overlap with the given byte range.
+    ///
+    /// Must be called after writing to the partition file.
+    pub fn invalidate(&self, file_path: &Path, start: u64, len: u64) {
+        let first_page = start / PAGE_SIZE;
+        let last_page = (start + len.max(1) - 1) / PAGE_SIZE;
+        for page_number in first_page..=last_page {
+            self.pages.remove(&(file_path.to_path_buf(), page_number));
+        }
+    }
+
+    /// Evicts the pages holding a record (header included), after writing it.
+    pub fn invalidate_record(&self, partition: &Partition, record_info: &RecordInformation) {
+        self.invalidate(partition.file_path(), record_info.header_start(), record_info.stored_size());
+    }
+
+    /// Evicts every page of a partition file, after rewriting it.
+    pub fn invalidate_file(&self, file_path: &Path) {
+        self.pages.retain(|(path, _), _| path != file_path);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.pages.stats()
+    }
+}
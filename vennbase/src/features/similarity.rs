@@ -0,0 +1,50 @@
+use image::imageops::{self, FilterType};
+use image::DynamicImage;
+
+/// Hamming distance used by `similar` requests that don't give a `threshold=`
+pub const DEFAULT_SIMILARITY_THRESHOLD: u32 = 10;
+
+/// Computes the difference hash (dHash) of an image.
+///
+/// The image is shrunk to 9x8 grayscale pixels, and every bit tells whether a pixel is
+/// brighter than the one at its right. Resized or re-encoded copies of an image get the
+/// same hash, or one that differs in a few bits.
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = imageops::resize(&img.to_luma8(), 9, 8, FilterType::Triangle);
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    hash
+}
+
+/// Number of bits that differ between two hashes, from 0 (same image) to 64.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn resized_copies_are_similar() {
+        // A horizontal gradient with a dark square in the middle
+        let img = RgbImage::from_fn(180, 160, |x, y| {
+            let dark = (60..120).contains(&x) && (50..110).contains(&y);
+            let value = if dark { 20 } else { (x * 255 / 180) as u8 };
+            Rgb([value, value, value])
+        });
+        let original = DynamicImage::ImageRgb8(img);
+        let copy = original.resize_exact(45, 40, FilterType::Nearest);
+        let flipped = original.fliph();
+
+        assert!(hamming_distance(dhash(&original), dhash(&copy)) <= 4);
+        assert!(hamming_distance(dhash(&original), dhash(&flipped)) > DEFAULT_SIMILARITY_THRESHOLD);
+    }
+}
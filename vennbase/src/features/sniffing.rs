@@ -0,0 +1,268 @@
+use serde::Deserialize;
+
+use image::DynamicImage;
+
+use crate::db::types::MimeType;
+use crate::features::resize::decode_record_image;
+
+/// What `save` does when the data doesn't look like its declared mimetype.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MimeValidation {
+    /// The declared mimetype is trusted
+    #[default]
+    Off,
+    /// Mismatching records are not saved
+    Reject,
+    /// Mismatching records are saved with the sniffed mimetype
+    Correct,
+}
+
+/// A magic number, or any other pattern that identifies a file format.
+struct Signature {
+    mime: &'static str,
+    matches: fn(&[u8]) -> bool,
+    /// Whether other formats are built on this one, like ZIP for EPUB or OGG for Opus. Such
+    /// data may be declared as any mimetype without a signature of its own
+    container: bool,
+}
+
+impl Signature {
+    const fn new(mime: &'static str, matches: fn(&[u8]) -> bool) -> Self {
+        Signature { mime, matches, container: false }
+    }
+
+    const fn container(mime: &'static str, matches: fn(&[u8]) -> bool) -> Self {
+        Signature { mime, matches, container: true }
+    }
+}
+
+/// Number of bytes at the start of a record that are enough to sniff its mimetype, when
+/// the whole record is not available
+pub const SNIFFED_HEAD_LENGTH: u64 = 64 * 1024;
+
+/// Brands of ISO-BMFF files (`ftyp` boxes) that only hold audio
+const AUDIO_MP4_BRANDS: [&[u8]; 3] = [b"M4A ", b"M4B ", b"M4P "];
+
+// Sorted from the most specific to the most generic
+const SIGNATURES: &[Signature] = &[
+    Signature::new("image/png", |data| data.starts_with(b"\x89PNG\r\n\x1a\n")),
+    Signature::new("image/jpeg", |data| data.starts_with(&[0xFF, 0xD8, 0xFF])),
+    Signature::new("image/gif", |data| data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")),
+    Signature::new("image/webp", |data| riff_form(data) == Some(b"WEBP")),
+    Signature::new("image/bmp", |data| data.starts_with(b"BM") && data.len() >= 26),
+    Signature::new("application/pdf", |data| data.starts_with(b"%PDF-")),
+    Signature::new("audio/wav", |data| riff_form(data) == Some(b"WAVE")),
+    Signature::new("audio/mpeg", |data| data.starts_with(b"ID3") || is_mpeg_audio_frame(data)),
+    Signature::new("audio/mp4", |data| ftyp_brand(data).is_some_and(|brand| AUDIO_MP4_BRANDS.contains(&brand))),
+    Signature::container("video/mp4", |data| ftyp_brand(data).is_some()),
+    Signature::container("video/webm", |data| data.starts_with(&[0x1A, 0x45, 0xDF, 0xA3])),
+    Signature::container("audio/ogg", |data| data.starts_with(b"OggS")),
+    Signature::container("application/zip", |data| data.starts_with(b"PK\x03\x04")),
+    Signature::new("text/html", looks_like_html),
+];
+
+/// The form type of a RIFF container, like `WEBP` or `WAVE`.
+fn riff_form(data: &[u8]) -> Option<&[u8]> {
+    if !data.starts_with(b"RIFF") {
+        return None;
+    }
+    data.get(8..12)
+}
+
+/// The major brand of an ISO-BMFF file (MP4, M4A, MOV...), like `isom` or `M4A `.
+fn ftyp_brand(data: &[u8]) -> Option<&[u8]> {
+    if data.get(4..8) != Some(b"ftyp") {
+        return None;
+    }
+    data.get(8..12)
+}
+
+/// MP3 files without an ID3 tag start right away with a frame header: 11 bits of frame
+/// sync, the MPEG version and a layer other than the reserved `00`.
+fn is_mpeg_audio_frame(data: &[u8]) -> bool {
+    match data {
+        [0xFF, header, ..] => header & 0xE0 == 0xE0 && header & 0x06 != 0,
+        _ => false,
+    }
+}
+
+fn looks_like_html(data: &[u8]) -> bool {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    let start = data.iter().position(|c| !c.is_ascii_whitespace()).unwrap_or(data.len());
+    let head = &data[start..data.len().min(start + 14)];
+    head.len() >= 5 && (
+        head.eq_ignore_ascii_case(b"<!doctype html") || head[..5].eq_ignore_ascii_case(b"<html")
+    )
+}
+
+/// Guesses the mimetype of some data from its first bytes.
+pub fn sniff_mime_type(data: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|signature| (signature.matches)(data))
+        .map(|signature| signature.mime)
+}
+
+/// Text that isn't recognized by a signature, like CSV or JSON
+fn looks_like_text(data: &[u8]) -> bool {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        // The first bytes of a big record may end in the middle of a character
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&data[..e.valid_up_to()]).unwrap(),
+        Err(_) => return false,
+    };
+    !text.is_empty() && !text.contains(|c: char| c.is_control() && !c.is_whitespace())
+}
+
+/// Detects the mimetype of the data of `save auto` requests.
+///
+/// Data that isn't recognized by a signature is `text/plain` if it looks like text, and
+/// `application/octet-stream` otherwise.
+pub fn detect_mime_type(data: &[u8]) -> &'static str {
+    match sniff_mime_type(data) {
+        Some(mimetype) => mimetype,
+        None if looks_like_text(data) => "text/plain",
+        None => "application/octet-stream",
+    }
+}
+
+/// Whether data of a mimetype is always recognized by `sniff_mime_type`.
+///
+/// Text formats are not, since their signatures only find some of them: HTML fragments
+/// are valid `text/html` but don't start like a document.
+fn has_signature(mimetype: &MimeType) -> bool {
+    !mimetype.as_str().starts_with("text/")
+        && SIGNATURES.iter().any(|signature| signature.mime == mimetype.as_str())
+}
+
+fn is_container(mime: &str) -> bool {
+    SIGNATURES.iter().any(|signature| signature.container && signature.mime == mime)
+}
+
+#[derive(Debug)]
+pub enum SniffError {
+    /// The data looks like another format, or like none of the known ones
+    Mismatch { declared: MimeType, sniffed: Option<&'static str> },
+    /// The data looks like a resizable image but it can't be decoded
+    Undecodable { mimetype: MimeType, reason: String },
+}
+
+impl std::fmt::Display for SniffError {
+    /// Formats the error as `<kind> <offset> <message>`, like query errors.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SniffError::Mismatch { declared, sniffed: Some(sniffed) } => {
+                write!(f, "mime-mismatch 0 declared {declared} but the data looks like {sniffed}")
+            },
+            SniffError::Mismatch { declared, sniffed: None } => {
+                write!(f, "mime-mismatch 0 declared {declared} but the data doesn't look like it")
+            },
+            SniffError::Undecodable { mimetype, reason } => {
+                write!(f, "undecodable-image 0 the data is not a valid {mimetype}: {reason}")
+            },
+        }
+    }
+}
+
+/// Checks that the data matches its declared mimetype, returning the mimetype it should
+/// be saved with, along with the decoded image if it's an image.
+///
+/// Text formats are hard to tell apart, so a text mimetype is only a mismatch when the
+/// declared format has a signature of its own. The same goes for containers like ZIP.
+///
+/// Images are decoded even if `validation` is off, so the caller doesn't decode them
+/// again to read their metadata or generate their variants.
+pub fn validate_mime_type(
+    declared: &MimeType,
+    data: &[u8],
+    validation: MimeValidation
+) -> Result<(MimeType, Option<DynamicImage>), SniffError> {
+    let mimetype = check_signature(declared, data, validation)?;
+
+    // Images that are served resized must be fully decodable, not just look like images
+    match decode_record_image(&mimetype, data) {
+        Some(Err(err)) if validation != MimeValidation::Off => {
+            Err(SniffError::Undecodable { mimetype, reason: err.to_string() })
+        },
+        image => Ok((mimetype, image.and_then(Result::ok))),
+    }
+}
+
+/// Like `validate_mime_type`, without decoding images. Signatures are found at the start
+/// of the data, so `head` can be just the first bytes of a record.
+pub fn check_signature(
+    declared: &MimeType,
+    head: &[u8],
+    validation: MimeValidation
+) -> Result<MimeType, SniffError> {
+    if validation == MimeValidation::Off {
+        return Ok(declared.clone());
+    }
+    let mismatch = match sniff_mime_type(head) {
+        Some(sniffed) if sniffed == declared.as_str() => None,
+        Some(sniffed) if sniffed.starts_with("text/") && !has_signature(declared) => None,
+        Some(sniffed) if is_container(sniffed) && !has_signature(declared) => None,
+        Some(sniffed) => Some(Some(sniffed)),
+        None if has_signature(declared) => Some(None),
+        None => None,
+    };
+    match (mismatch, validation) {
+        (None, _) => Ok(declared.clone()),
+        (Some(Some(sniffed)), MimeValidation::Correct) => Ok(MimeType::from(sniffed).expect("signatures to be valid mimetypes")),
+        (Some(sniffed), _) => Err(SniffError::Mismatch { declared: declared.clone(), sniffed }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::vennbase::testing;
+
+    #[test]
+    fn mismatches_are_rejected_or_corrected() {
+        let html = b"  <!DOCTYPE html><p>Not an image</p>";
+        let png = MimeType::from("image/png").unwrap();
+
+        let err = validate_mime_type(&png, html, MimeValidation::Reject).unwrap_err();
+        assert!(matches!(err, SniffError::Mismatch { sniffed: Some("text/html"), .. }));
+        let (corrected, image) = validate_mime_type(&png, html, MimeValidation::Correct).unwrap();
+        assert_eq!(corrected.as_str(), "text/html");
+        assert!(image.is_none());
+
+        // Text can't be told apart from other text formats
+        let plain = MimeType::from("text/plain").unwrap();
+        assert_eq!(validate_mime_type(&plain, html, MimeValidation::Reject).unwrap().0.as_str(), "text/plain");
+
+        // Only whole documents are recognized as HTML, fragments are valid too
+        let html_type = MimeType::from("text/html").unwrap();
+        assert!(validate_mime_type(&html_type, b"<p>A fragment</p>", MimeValidation::Reject).is_ok());
+        let err = validate_mime_type(&html_type, b"\x89PNG\r\n\x1a\n", MimeValidation::Reject).unwrap_err();
+        assert!(matches!(err, SniffError::Mismatch { sniffed: Some("image/png"), .. }));
+
+        // EPUB books are ZIP files
+        let epub = MimeType::from("application/epub+zip").unwrap();
+        assert!(validate_mime_type(&epub, b"PK\x03\x04\x14\x00", MimeValidation::Reject).is_ok());
+
+        // Looking like a PNG is not enough to be resized
+        let err = validate_mime_type(&png, b"\x89PNG\r\n\x1a\ngarbage", MimeValidation::Correct).unwrap_err();
+        assert!(matches!(err, SniffError::Undecodable { .. }));
+        let (_, image) = validate_mime_type(&png, b"\x89PNG\r\n\x1a\ngarbage", MimeValidation::Off).unwrap();
+        assert!(image.is_none());
+
+        // Valid images are decoded once, for the caller to use
+        let (_, image) = validate_mime_type(&png, &testing::png(8, 4), MimeValidation::Reject).unwrap();
+        assert_eq!(image.map(|image| (image.width(), image.height())), Some((8, 4)));
+    }
+
+    #[test]
+    fn auto_mime_types_are_detected() {
+        assert_eq!(detect_mime_type(b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00"), "video/mp4");
+        assert_eq!(detect_mime_type(b"\x00\x00\x00\x20ftypM4A \x00\x00\x00\x00"), "audio/mp4");
+        assert_eq!(detect_mime_type(b"RIFF\x24\x00\x00\x00WAVEfmt "), "audio/wav");
+        assert_eq!(detect_mime_type(&[0xFF, 0xFB, 0x90, 0x64]), "audio/mpeg");
+        assert_eq!(detect_mime_type("name,width\nrosé,1080\n".as_bytes()), "text/plain");
+        assert_eq!(detect_mime_type(b"\x00\x01\x02binary"), "application/octet-stream");
+    }
+}
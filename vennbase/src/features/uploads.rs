@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use serde::{Deserialize, Serialize};
+
+use crate::db::types::MimeType;
+use crate::features::sniffing::SniffError;
+use crate::features::encryption::SideFile;
+
+/// Multi-part uploads that were begun but not committed yet.
+///
+/// The parts are stored in the `.chunks` partition as soon as they are received, so
+/// only their ids are kept here. Uploads survive restarts until they are committed or
+/// aborted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadsMap {
+    #[serde(skip)]
+    pub file: SideFile,
+    pub uploads: HashMap<uuid::Uuid, PendingUpload>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingUpload {
+    /// Declared mimetype, or `None` to detect it when the upload is committed
+    pub mimetype: Option<MimeType>,
+    pub tags: Vec<String>,
+    /// Ids of the chunks by part number, starting from 1
+    pub parts: BTreeMap<u32, uuid::Uuid>,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    NotFound(uuid::Uuid),
+    InvalidPart(String),
+    /// The parts are not contiguous from 1
+    MissingPart(u32),
+    Mime(SniffError),
+    IoError(io::Error),
+}
+
+impl std::fmt::Display for UploadError {
+    /// Formats the error as `<kind> <offset> <message>`, like query errors.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::NotFound(id) => write!(f, "unknown-upload 0 upload '{id}' doesn't exist"),
+            UploadError::InvalidPart(reason) => write!(f, "invalid-part 0 {reason}"),
+            UploadError::MissingPart(part) => write!(f, "missing-part 0 part {part} was not uploaded"),
+            UploadError::Mime(err) => write!(f, "{err}"),
+            UploadError::IoError(err) => write!(f, "io 0 {err}"),
+        }
+    }
+}
+
+impl From<SniffError> for UploadError {
+    fn from(err: SniffError) -> Self {
+        UploadError::Mime(err)
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(err: io::Error) -> Self {
+        UploadError::IoError(err)
+    }
+}
+
+impl UploadsMap {
+    /// Loads the pending uploads from `path`. Databases created before multi-part
+    /// uploads existed don't have the file, so an empty map is returned in that case.
+    pub fn from_file(file: SideFile) -> io::Result<Self> {
+        if !file.exists() {
+            return Ok(UploadsMap { file, uploads: HashMap::new() });
+        }
+        let mut uploads = file.read_json::<UploadsMap>()?;
+        uploads.file = file;
+        Ok(uploads)
+    }
+
+    fn flush_data(&self) -> io::Result<()> {
+        self.file.write_json(self)
+    }
+
+    pub fn get(&self, id: &uuid::Uuid) -> Option<&PendingUpload> {
+        self.uploads.get(id)
+    }
+
+    pub fn begin(&mut self, mimetype: Option<MimeType>, tags: Vec<String>) -> io::Result<uuid::Uuid> {
+        let id = uuid::Uuid::new_v4();
+        self.uploads.insert(id, PendingUpload { mimetype, tags, parts: BTreeMap::new() });
+        self.flush_data()?;
+        Ok(id)
+    }
+
+    /// Stores the chunk of a part, returning the chunk it replaces, if any.
+    pub fn set_part(&mut self, id: &uuid::Uuid, part: u32, chunk: uuid::Uuid) -> io::Result<Option<uuid::Uuid>> {
+        let Some(upload) = self.uploads.get_mut(id) else {
+            return Ok(None);
+        };
+        let replaced = upload.parts.insert(part, chunk);
+        self.flush_data()?;
+        Ok(replaced)
+    }
+
+    pub fn remove(&mut self, id: &uuid::Uuid) -> io::Result<Option<PendingUpload>> {
+        let upload = self.uploads.remove(id);
+        if upload.is_some() {
+            self.flush_data()?;
+        }
+        Ok(upload)
+    }
+
+    /// Chunks of all the pending uploads.
+    pub fn chunk_ids(&self) -> impl Iterator<Item=&uuid::Uuid> {
+        self.uploads.values().flat_map(|upload| upload.parts.values())
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::io;
+use serde::{Deserialize, Serialize};
+
+use crate::query::QueryError;
+use crate::features::encryption::SideFile;
+
+pub const MAX_VIEW_NAME_LENGTH: usize = 64;
+
+/// Saved queries that can be referenced from other queries with `view:<name>`.
+///
+/// The queries are stored as they were written by the client, and they are expanded
+/// every time a query references them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ViewsMap {
+    #[serde(skip)]
+    pub file: SideFile,
+    pub views: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum ViewError {
+    InvalidName(String),
+    AlreadyExists(String),
+    NotFound(String),
+    /// The view can't be dropped because other views reference it
+    InUse(String, Vec<String>),
+    Query(QueryError),
+    IoError(io::Error),
+}
+
+impl std::fmt::Display for ViewError {
+    /// Formats the error as `<kind> <offset> <message>`, like query errors.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ViewError::InvalidName(name) => write!(f, "invalid-view-name 0 invalid view name '{name}'"),
+            ViewError::AlreadyExists(name) => write!(f, "view-exists 0 view '{name}' already exists"),
+            ViewError::NotFound(name) => write!(f, "unknown-view 0 view '{name}' doesn't exist"),
+            ViewError::InUse(name, users) => {
+                write!(f, "view-in-use 0 view '{name}' is used by: {}", users.join(", "))
+            },
+            ViewError::Query(err) => write!(f, "{err}"),
+            ViewError::IoError(err) => write!(f, "io 0 {err}"),
+        }
+    }
+}
+
+impl From<QueryError> for ViewError {
+    fn from(err: QueryError) -> Self {
+        ViewError::Query(err)
+    }
+}
+
+impl From<io::Error> for ViewError {
+    fn from(err: io::Error) -> Self {
+        ViewError::IoError(err)
+    }
+}
+
+impl ViewsMap {
+    /// Loads the views from `file`. Databases created before views existed don't have
+    /// the file, so an empty map is returned in that case.
+    pub fn from_file(file: SideFile) -> io::Result<Self> {
+        if !file.exists() {
+            return Ok(ViewsMap { file, views: HashMap::new() });
+        }
+        let mut views = file.read_json::<ViewsMap>()?;
+        views.file = file;
+        Ok(views)
+    }
+
+    fn flush_data(&self) -> io::Result<()> {
+        self.file.write_json(self)
+    }
+
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= MAX_VIEW_NAME_LENGTH
+            && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.views.get(name).map(String::as_str)
+    }
+
+    /// Stores a new view. The caller is responsible of validating the query.
+    pub fn create(&mut self, name: &str, query: &str) -> Result<(), ViewError> {
+        if !ViewsMap::is_valid_name(name) {
+            return Err(ViewError::InvalidName(name.to_owned()));
+        }
+        if self.views.contains_key(name) {
+            return Err(ViewError::AlreadyExists(name.to_owned()));
+        }
+        self.views.insert(name.to_owned(), query.to_owned());
+        self.flush_data()?;
+        Ok(())
+    }
+
+    /// Removes a view, unless some other view references it.
+    pub fn drop_view(&mut self, name: &str) -> Result<(), ViewError> {
+        if !self.views.contains_key(name) {
+            return Err(ViewError::NotFound(name.to_owned()));
+        }
+        let reference = format!("view:{name}");
+        let mut users = self.views
+            .iter()
+            .filter(|(_, query)| {
+                query
+                    .split(|c: char| !(c.is_alphanumeric() || "_-:*/".contains(c)))
+                    .any(|identifier| identifier == reference)
+            })
+            .map(|(user, _)| user.to_owned())
+            .collect::<Vec<_>>();
+        if !users.is_empty() {
+            users.sort();
+            return Err(ViewError::InUse(name.to_owned(), users));
+        }
+        self.views.remove(name);
+        self.flush_data()?;
+        Ok(())
+    }
+
+    /// Returns the views sorted by name.
+    pub fn list(&self) -> Vec<(&str, &str)> {
+        let mut views = self.views
+            .iter()
+            .map(|(name, query)| (name.as_str(), query.as_str()))
+            .collect::<Vec<_>>();
+        views.sort();
+        views
+    }
+}
@@ -1,19 +1,261 @@
 use std::collections::HashMap;
 
 use logic_parser::lexing::Lexer;
+use logic_parser::lexing::token::{Span, Token, TokenKind};
 use logic_parser::parsing::{Parser, ASTNode};
 use logic_parser::errors::{LexerError, ParserError};
 
-pub fn parse_query(query: &str) -> logic_parser::parsing::Result<ASTNode> {
-    let mut lexer = Lexer::with_alphabets(
-        |c| c.is_alphanumeric() || c == '_' || c == '-' || c == ':' || c == '*' || c == '/',
-        |c| c.is_alphabetic(),
-    );
+/// Filters that can be used in query identifiers, like `tag:anime`.
+pub const KNOWN_FILTERS: [&str; 5] = ["mime", "tag", "id", "view", "orientation"];
 
-    let tokens = lexer.tokenize(query).map_err(<LexerError as Into<ParserError>>::into)?;
+/// Image properties that can be compared with numbers in query identifiers, like
+/// `width>=1080`.
+pub const COMPARABLE_FIELDS: [&str; 2] = ["width", "height"];
+
+// Longest operators first, so `<=` is not read as `<`
+const COMPARISON_OPERATORS: [(&str, Comparison); 5] = [
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+    ("=", Comparison::Equal),
+];
+
+// Logical operators that share their characters with the comparison operators
+const LOGICAL_OPERATORS: [&str; 3] = ["<=>", "<->", "=>"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    pub fn holds(&self, left: u32, right: u32) -> bool {
+        match self {
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Equal => left == right,
+            Comparison::GreaterOrEqual => left >= right,
+            Comparison::Greater => left > right,
+        }
+    }
+}
+
+/// Splits a comparison identifier like `width>=1080` into its field, operator and value.
+///
+/// Returns `None` if the identifier is not a well-formed comparison.
+pub fn parse_comparison(identifier: &str) -> Option<(&str, Comparison, u32)> {
+    let op_i = identifier.find(['<', '>', '='])?;
+    let (field, rest) = identifier.split_at(op_i);
+    let (op, comparison) = COMPARISON_OPERATORS.iter().find(|(op, _)| rest.starts_with(op))?;
+    let value = rest[op.len()..].parse::<u32>().ok()?;
+    Some((field, *comparison, value))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryErrorKind {
+    /// The query contains characters or operators that can't be tokenized
+    Lex,
+    /// The tokens don't form a valid expression
+    Parse,
+    /// An identifier uses a filter that is not in `KNOWN_FILTERS`
+    UnknownFilter,
+    /// An identifier is not of the form `<filter>:<value>`
+    MalformedIdentifier,
+    /// A `view:` identifier references a view that doesn't exist
+    UnknownView,
+    /// The query was valid but couldn't be evaluated
+    Evaluation,
+}
+
+/// An error found while parsing or evaluating a query.
+///
+/// The offset is the position (in characters, not bytes) of the offending part of the query.
+#[derive(Debug)]
+pub struct QueryError {
+    pub kind: QueryErrorKind,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for QueryErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            QueryErrorKind::Lex => "lex",
+            QueryErrorKind::Parse => "parse",
+            QueryErrorKind::UnknownFilter => "unknown-filter",
+            QueryErrorKind::MalformedIdentifier => "malformed-identifier",
+            QueryErrorKind::UnknownView => "unknown-view",
+            QueryErrorKind::Evaluation => "evaluation",
+        })
+    }
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.kind, self.offset, self.message)
+    }
+}
+
+impl QueryError {
+    /// Builds an error from a byte offset of the query.
+    fn at(query: &str, byte_offset: usize, kind: QueryErrorKind, message: String) -> Self {
+        let byte_offset = byte_offset.min(query.len());
+        QueryError {
+            kind,
+            offset: query[..byte_offset].chars().count(),
+            message,
+        }
+    }
+
+    fn from_parser_error(query: &str, err: ParserError) -> Self {
+        match err {
+            ParserError::LexingError(LexerError::SyntaxError(message, span)) => {
+                QueryError::at(query, span.start, QueryErrorKind::Lex, message)
+            },
+            ParserError::LexingError(LexerError::UnknownToken(c, span)) => {
+                QueryError::at(query, span.start, QueryErrorKind::Lex, format!("unknown token '{c}'"))
+            },
+            ParserError::UnexpectedToken(token, span) => {
+                QueryError::at(query, span.start, QueryErrorKind::Parse, format!("unexpected token {token}"))
+            },
+            ParserError::UnexpectedEOF(message, span) => {
+                // The span is the one of the last token, the error is right after it
+                QueryError::at(query, span.end, QueryErrorKind::Parse, format!("unexpected end of query: {message}"))
+            },
+        }
+    }
+}
+
+pub fn parse_query(query: &str) -> Result<ASTNode, QueryError> {
+    parse_query_checked(query, |_| Ok(()))
+}
+
+/// Parses a query, running `check` on every well-formed identifier.
+///
+/// `check` returns the byte offset within the identifier, the kind and the message of
+/// the error, just like the validation of identifiers does.
+pub fn parse_query_checked<F>(query: &str, check: F) -> Result<ASTNode, QueryError>
+where F: Fn(&str) -> Result<(), (usize, QueryErrorKind, String)> {
+    // The lexer reads a placeholder word in place of every comparison, which keeps the
+    // spans of the other tokens
+    let comparisons = find_comparisons(query);
+    let mut masked = query.to_owned();
+    for &(start, end) in &comparisons {
+        masked.replace_range(start..end, &"x".repeat(end - start));
+    }
+    let mut lexer = Lexer::with_alphabets(is_identifier_char, |c| c.is_alphabetic());
+
+    let tokens = lexer.tokenize(&masked)
+        .map_err(|e| QueryError::from_parser_error(query, e.into()))?
+        .into_iter()
+        .map(|token| {
+            let Span { start, end } = token.span;
+            if comparisons.contains(&(start, end)) {
+                Token::new(TokenKind::Identifier(query[start..end].into()), token.span)
+            }
+            else {
+                token
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Identifiers are validated before parsing, since the parsed tree loses their positions
+    for token in &tokens {
+        if let TokenKind::Identifier(identifier) = &token.kind {
+            validate_identifier(identifier)
+                .and_then(|_| check(identifier))
+                .map_err(|(offset, kind, message)| {
+                    QueryError::at(query, token.span.start + offset, kind, message)
+                })?;
+        }
+    }
 
     let mut parser = Parser::new(&tokens);
-    parser.parse()
+    parser.parse().map_err(|e| QueryError::from_parser_error(query, e))
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == ':' || c == '*' || c == '/'
+}
+
+/// Finds the byte spans of the comparisons of a query, like `width>=1080`.
+///
+/// A comparison is one of the `COMPARISON_OPERATORS` along with the identifier characters
+/// around it. The logical operators that look like them (`=>`, `<=>`, `<->` and `->`) are
+/// left to the lexer.
+fn find_comparisons(query: &str) -> Vec<(usize, usize)> {
+    let mut comparisons = Vec::new();
+    let mut i = 0;
+    while let Some(offset) = query[i..].find(['<', '>', '=']) {
+        let op_start = i + offset;
+        let rest = &query[op_start..];
+        if let Some(op) = LOGICAL_OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            i = op_start + op.len();
+            continue;
+        }
+        if rest.starts_with('>') && query[..op_start].ends_with('-') {
+            i = op_start + 1;
+            continue;
+        }
+        let (op, _) = COMPARISON_OPERATORS
+            .iter()
+            .find(|(op, _)| rest.starts_with(op))
+            .expect("to exist since every operator character starts one");
+        let start = query[i..op_start]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !is_identifier_char(*c))
+            .map_or(i, |(j, c)| i + j + c.len_utf8());
+        let value = &rest[op.len()..];
+        let end = op_start + op.len() + value.find(|c| !is_identifier_char(c)).unwrap_or(value.len());
+        comparisons.push((start, end));
+        i = end;
+    }
+    comparisons
+}
+
+/// Checks that an identifier has the form `<filter>:<value>` with a known filter, or
+/// `<field><operator><number>` with a comparable field.
+///
+/// On error, returns the byte offset of the problem within the identifier.
+fn validate_identifier(identifier: &str) -> Result<(), (usize, QueryErrorKind, String)> {
+    use QueryErrorKind::*;
+
+    if let Some(op_i) = identifier.find(['<', '>', '=']) {
+        let field = &identifier[..op_i];
+        if !COMPARABLE_FIELDS.contains(&field) {
+            return Err((0, UnknownFilter, format!("unknown comparable field '{field}'")));
+        }
+        if parse_comparison(identifier).is_none() {
+            return Err((
+                op_i,
+                MalformedIdentifier,
+                format!("expected '{field}<operator><number>' but found '{identifier}'")
+            ));
+        }
+        return Ok(());
+    }
+
+    let (filter, value) = identifier.split_once(':').ok_or_else(|| (
+        identifier.len(),
+        MalformedIdentifier,
+        format!("expected '<filter>:<value>' but found '{identifier}'")
+    ))?;
+    if !KNOWN_FILTERS.contains(&filter) {
+        return Err((0, UnknownFilter, format!("unknown filter '{filter}:'")));
+    }
+    if value.is_empty() {
+        return Err((identifier.len(), MalformedIdentifier, format!("empty value for filter '{filter}:'")));
+    }
+    if let Some(colon_i) = value.find(':') {
+        return Err((filter.len() + 1 + colon_i, MalformedIdentifier, format!("unexpected ':' in '{identifier}'")));
+    }
+    Ok(())
 }
 
 // This enum differentiates between fixed-value propositions and fickle ones
@@ -70,7 +312,7 @@ impl<'a> Iterator for VariablesPermutations<'a> {
         let mut fickle_i = 0;
         // This will generate a new permutation for the fickle variables
         // according to the current iteration self.i
-        let evaluations = self.variables.iter().enumerate().map(|(_, variable)| {
+        let evaluations = self.variables.iter().map(|variable| {
             match variable {
                 // If the proposition is fixed, just return it
                 Fixed(value) => *value,
@@ -115,3 +357,142 @@ pub fn evaluate(tree: &ASTNode, values: &HashMap<String, bool>) -> Result<bool,
         },
     }
 }
+
+/// Renders a query tree back to its textual form, fully parenthesized.
+///
+/// The operands of commutative operators are sorted, so equivalent queries like
+/// `tag:a && tag:b` and `tag:b && tag:a` render to the same string.
+pub fn normalize(tree: &ASTNode) -> String {
+    fn binary(left: &ASTNode, op: &str, right: &ASTNode, commutative: bool) -> String {
+        let (mut left, mut right) = (normalize(left), normalize(right));
+        if commutative && right < left {
+            std::mem::swap(&mut left, &mut right);
+        }
+        format!("({left} {op} {right})")
+    }
+
+    match tree {
+        ASTNode::Not { operand } => format!("!{}", normalize(operand)),
+        ASTNode::And { left, right } => binary(left, "&&", right, true),
+        ASTNode::Or { left, right } => binary(left, "||", right, true),
+        ASTNode::Implies { left, right } => binary(left, "=>", right, false),
+        ASTNode::IfAndOnlyIf { left, right } => binary(left, "<=>", right, true),
+        ASTNode::Literal { value } => value.to_string(),
+        ASTNode::Identifier { name } => name.to_owned(),
+    }
+}
+
+/// Describes how a single identifier of the query will be resolved.
+#[derive(Debug)]
+pub enum IndexLookup {
+    /// A `tag:` lookup on the inverted index, with the number of records holding the tag
+    Tag { tag: String, cardinality: usize },
+    /// An `id:` lookup, which matches at most one record
+    Id { id: String, cardinality: usize },
+    /// A `mime:` filter, resolved by partition pruning
+    Mime { pattern: String, cardinality: usize },
+    /// A filter on the image metadata, like `width>=1080`, with the number of images
+    /// matching it
+    Metadata { identifier: String, cardinality: usize },
+    /// An identifier whose filter is not known by the database
+    Unknown { identifier: String },
+}
+
+/// The execution plan of a query, as returned by `Vennbase::explain_query`.
+#[derive(Debug)]
+pub struct QueryPlan {
+    pub normalized: String,
+    /// Partitions that will be scanned, with their number of active records
+    pub scanned: Vec<(String, usize)>,
+    /// Partitions that can't contain a match, so they will be skipped
+    pub skipped: Vec<(String, usize)>,
+    pub lookups: Vec<IndexLookup>,
+}
+
+impl QueryPlan {
+    /// Upper bound of the number of records the query can match.
+    pub fn estimated_rows(&self) -> usize {
+        self.scanned.iter().map(|(_, records)| records).sum()
+    }
+
+    /// Lines of the plan, in the same order they are sent to the client.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("query {}", self.normalized)];
+        for (mimetype, records) in &self.scanned {
+            lines.push(format!("scan {mimetype} ~{records}"));
+        }
+        for (mimetype, records) in &self.skipped {
+            lines.push(format!("skip {mimetype} ~{records}"));
+        }
+        for lookup in &self.lookups {
+            lines.push(match lookup {
+                IndexLookup::Tag { tag, cardinality } => format!("lookup tag:{tag} ~{cardinality}"),
+                IndexLookup::Id { id, cardinality } => format!("lookup id:{id} ~{cardinality}"),
+                IndexLookup::Mime { pattern, cardinality } => format!("lookup mime:{pattern} ~{cardinality}"),
+                IndexLookup::Metadata { identifier, cardinality } => format!("lookup {identifier} ~{cardinality}"),
+                IndexLookup::Unknown { identifier } => format!("unknown {identifier}"),
+            });
+        }
+        lines.push(format!("rows ~{}", self.estimated_rows()));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_sorts_commutative_operands() {
+        let a = parse_query("tag:b && (mime:image/* || tag:a)").unwrap();
+        let b = parse_query("(tag:a || mime:image/*) && tag:b").unwrap();
+        assert_eq!(normalize(&a), normalize(&b));
+        assert_eq!(normalize(&a), "((mime:image/* || tag:a) && tag:b)");
+    }
+
+    #[test]
+    fn errors_point_to_the_offending_identifier() {
+        let err = parse_query("tag:a && ñu:b").unwrap_err();
+        assert_eq!(err.kind, QueryErrorKind::UnknownFilter);
+        assert_eq!(err.offset, 9);
+
+        let err = parse_query("mime:image/* || tag:").unwrap_err();
+        assert_eq!(err.kind, QueryErrorKind::MalformedIdentifier);
+        assert_eq!(err.offset, 20);
+
+        let err = parse_query("(tag:a && tag:b").unwrap_err();
+        assert_eq!(err.kind, QueryErrorKind::Parse);
+
+        let err = parse_query("tag:a $ tag:b").unwrap_err();
+        assert_eq!(err.kind, QueryErrorKind::Lex);
+        assert_eq!(err.offset, 6);
+    }
+
+    #[test]
+    fn comparisons_are_identifiers() {
+        let tree = parse_query("width>=1080 && height<720").unwrap();
+        assert_eq!(normalize(&tree), "(height<720 && width>=1080)");
+        assert_eq!(parse_comparison("width>=1080"), Some(("width", Comparison::GreaterOrEqual, 1080)));
+
+        // Operators written without spaces are still operators
+        let tree = parse_query("tag:a=>width=10<=>tag:b").unwrap();
+        assert_eq!(normalize(&tree), "(tag:a => (tag:b <=> width=10))");
+
+        let err = parse_query("tag:a && depth>3").unwrap_err();
+        assert_eq!((err.kind, err.offset), (QueryErrorKind::UnknownFilter, 9));
+        let err = parse_query("width>=wide").unwrap_err();
+        assert_eq!((err.kind, err.offset), (QueryErrorKind::MalformedIdentifier, 5));
+
+        // `=>` and `<=>` are never part of an identifier
+        let tree = parse_query("tag:a=>tag:b<=>tag:c").unwrap();
+        assert_eq!(normalize(&tree), "(tag:a => (tag:b <=> tag:c))");
+        let err = parse_query("tag:a => tag:b=").unwrap_err();
+        assert_eq!((err.kind, err.offset), (QueryErrorKind::UnknownFilter, 9));
+    }
+
+    #[test]
+    fn normalize_keeps_implication_order() {
+        let tree = parse_query("tag:b => tag:a").unwrap();
+        assert_eq!(normalize(&tree), "(tag:b => tag:a)");
+    }
+}